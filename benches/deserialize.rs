@@ -0,0 +1,61 @@
+//! Deserialization of the presence wrappers compared to plain `Option`.
+//!
+//! Each payload is an array of `n` objects so the cost of the visitors shows up across sizes.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde::Deserialize;
+use serde_json::json;
+use upsert_sql::{Lenient, Maybe, Required};
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Plain {
+    #[serde(default)]
+    one: Option<String>,
+    #[serde(default)]
+    two: Option<String>,
+    #[serde(default)]
+    count: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Wrapped {
+    one: Required<String>,
+    #[serde(default)]
+    two: Maybe<String>,
+    #[serde(default)]
+    count: Option<Lenient<i64>>,
+}
+
+fn payload(n: usize) -> String {
+    let items = (0..n)
+        .map(|i| match i % 3 {
+            0 => json!({ "one": "a", "two": "b", "count": 1 }),
+            1 => json!({ "one": null, "count": "2" }),
+            _ => json!({ "one": "c" }),
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string(&items).unwrap()
+}
+
+fn deserialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+    for n in [1, 100, 10_000] {
+        let json = payload(n);
+        group.throughput(Throughput::Bytes(json.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("option", n), &json, |b, json| {
+            // `Plain` can't parse the lenient counts, so strip them for the baseline
+            let json = json.replace(r#""2""#, "2");
+            b.iter(|| serde_json::from_str::<Vec<Plain>>(&json).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("wrapped", n), &json, |b, json| {
+            b.iter(|| serde_json::from_str::<Vec<Wrapped>>(json).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, deserialize);
+criterion_main!(benches);
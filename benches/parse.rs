@@ -0,0 +1,58 @@
+//! Parsing whole patches: plain deserialization, the limits check in front of it, and the depth
+//! scan on its own.
+//!
+//! Run with `cargo bench --features internals --bench parse`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::json;
+use upsert_sql::{internals, PatchLimits};
+
+/// A patch with `n` keys in `metadata`, so the size grows while the nesting stays the same.
+fn patch(n: usize) -> String {
+    let metadata = (0..n)
+        .map(|i| {
+            (
+                format!("key{}", i),
+                json!({ "tags": ["a", "b"], "count": i }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let patch = json!({
+        "one": "a",
+        "two": null,
+        "status": "active",
+        "locale": " en ",
+        "metadata": metadata,
+        "profile": { "bio": "" },
+    });
+    serde_json::to_string(&patch).unwrap()
+}
+
+fn parse(c: &mut Criterion) {
+    let limits = PatchLimits {
+        max_bytes: usize::MAX,
+        ..PatchLimits::default()
+    };
+
+    let mut group = c.benchmark_group("parse");
+    for n in [1, 100, 10_000] {
+        let json = patch(n);
+        group.throughput(Throughput::Bytes(json.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("from_json", n), &json, |b, json| {
+            b.iter(|| internals::from_json(json).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("from_json_with_limits", n),
+            &json,
+            |b, json| b.iter(|| internals::from_json_with_limits(json, &limits).unwrap()),
+        );
+        group.bench_with_input(BenchmarkId::new("depth", n), &json, |b, json| {
+            b.iter(|| internals::depth(json))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
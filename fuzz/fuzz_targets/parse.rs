@@ -0,0 +1,25 @@
+//! Feed arbitrary bytes to patch parsing. Parsing may fail but must never panic.
+//!
+//! `limits::depth` is a hand-written scanner that runs before serde, so it sees every malformed
+//! input. The bytes are converted lossily since patches arrive as `&str`.
+//!
+//! Run with `cargo +nightly fuzz run parse`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use upsert_sql::{internals, PatchLimits};
+
+fuzz_target!(|data: &[u8]| {
+    let json = String::from_utf8_lossy(data);
+
+    let depth = internals::depth(&json);
+    assert!(depth <= json.len());
+
+    let limits = PatchLimits::default();
+    let result = internals::from_json_with_limits(&json, &limits);
+    if depth > limits.max_depth && json.len() <= limits.max_bytes {
+        assert!(result.is_err());
+    }
+    let _ = internals::from_json(&json);
+});
@@ -0,0 +1,28 @@
+//! Feed arbitrary bytes to the presence wrappers. Parsing may fail but must never panic.
+//!
+//! Run with `cargo +nightly fuzz run presence`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+use upsert_sql::{Lenient, Maybe, Required};
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Payload {
+    one: Required<String>,
+    #[serde(default)]
+    two: Maybe<serde_json::Value>,
+    #[serde(default)]
+    count: Option<Lenient<i64>>,
+    #[serde(default)]
+    flag: Maybe<Lenient<bool>>,
+    #[serde(default)]
+    nested: Maybe<Vec<Required<Lenient<u8>>>>,
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_json::from_slice::<Payload>(data);
+    let _ = serde_json::from_slice::<Vec<Payload>>(data);
+});
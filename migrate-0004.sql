@@ -0,0 +1,4 @@
+create table user_profiles (
+    internal_id bigint primary key references users (internal_id)
+    , bio varchar
+);
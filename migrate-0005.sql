@@ -0,0 +1,3 @@
+create type user_status as enum ('active', 'suspended');
+
+alter table users add column status user_status;
@@ -0,0 +1 @@
+alter table users add column locale varchar not null default 'en';
@@ -0,0 +1,3 @@
+alter table users add column organization_id bigint;
+
+create index users_organization_id on users (organization_id);
@@ -0,0 +1,17 @@
+create table users_history (
+    history_id bigserial primary key
+    , id bigint not null
+    , internal_id bigint not null references users (internal_id)
+    , one varchar
+    , two varchar
+    , metadata jsonb
+    , deleted_at timestamptz
+    , status user_status
+    , locale varchar not null
+    , organization_id bigint
+    , bio varchar
+    , valid_from timestamptz not null
+    , valid_to timestamptz
+);
+
+create unique index users_history_current on users_history (internal_id) where valid_to is null;
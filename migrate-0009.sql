@@ -0,0 +1,10 @@
+create table patches (
+    patch_id bigserial primary key
+    , internal_id bigint not null references users (internal_id)
+    , patch jsonb not null
+    , actor varchar
+    , request_id varchar
+    , applied_at timestamptz not null default now()
+);
+
+create index patches_internal_id on patches (internal_id, patch_id);
@@ -0,0 +1 @@
+alter table users add column source_updated_at timestamptz;
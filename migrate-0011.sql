@@ -0,0 +1,5 @@
+-- generated so it's kept up to date by postgres however `one` and `two` are patched
+alter table users add column search tsvector
+    generated always as (to_tsvector('simple', coalesce(one, '') || ' ' || coalesce(two, ''))) stored;
+
+create index users_search on users using gin (search);
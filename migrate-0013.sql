@@ -0,0 +1 @@
+create extension if not exists hstore;
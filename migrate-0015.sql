@@ -0,0 +1,10 @@
+-- no foreign key since the key is claimed before the user is inserted
+create table idempotency_keys (
+    internal_id bigint not null
+    , key varchar not null
+    , outcome varchar
+    , id bigint
+    , changed_fields varchar[]
+    , created_at timestamptz not null default now()
+    , primary key (internal_id, key)
+);
@@ -0,0 +1,2 @@
+-- null for keys claimed before the hash was stored, those replay without being checked
+alter table idempotency_keys add column patch_hash varchar;
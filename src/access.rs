@@ -0,0 +1,114 @@
+//! Which fields a caller may read and write.
+//!
+//! The same `FieldPolicy` is checked when applying a patch and when fetching with
+//! `fetch_masked`, so a caller can't read a field through one path that it may not through the
+//! other. Fields are named as in patches, so the profile's bio is `profile.bio`.
+
+use crate::{
+    projection::{fetch_columns, UserColumn},
+    DbPool, Error, InternalId, Options, Update, FIELDS,
+};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+pub(crate) trait FieldPolicy: Send + Sync {
+    fn can_read(&self, field: &str) -> bool;
+
+    fn can_write(&self, field: &str) -> bool;
+}
+
+/// A fixed set of readable and writable fields, typically one per role.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FieldAccess {
+    read: HashSet<&'static str>,
+    write: HashSet<&'static str>,
+}
+
+impl FieldAccess {
+    /// Allow reading `fields`.
+    pub(crate) fn read(mut self, fields: &[&'static str]) -> Self {
+        self.read.extend(fields);
+        self
+    }
+
+    /// Allow reading and writing `fields`.
+    pub(crate) fn write(mut self, fields: &[&'static str]) -> Self {
+        self.read.extend(fields);
+        self.write.extend(fields);
+        self
+    }
+}
+
+impl FieldPolicy for FieldAccess {
+    fn can_read(&self, field: &str) -> bool {
+        self.read.contains(field)
+    }
+
+    fn can_write(&self, field: &str) -> bool {
+        self.write.contains(field)
+    }
+}
+
+impl Update {
+    /// Fail with `Error::Forbidden` if the patch has fields, `null` included, that `policy`
+    /// doesn't allow writing.
+    pub(crate) fn check_writable(&self, policy: &dyn FieldPolicy) -> Result<(), Error> {
+        let forbidden = FIELDS
+            .iter()
+            .copied()
+            .filter(|field| self.presence(field).is_present() && !policy.can_write(field))
+            .collect::<Vec<_>>();
+
+        if forbidden.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(forbidden))
+        }
+    }
+}
+
+/// Fetch the fields of a user that `policy` allows reading, as a JSON object keyed by field name.
+/// Other fields are left out, and fields in `options.encryption` are decrypted.
+pub(crate) async fn fetch_masked(
+    pool: &DbPool,
+    internal_id: InternalId,
+    policy: &dyn FieldPolicy,
+    options: &Options,
+) -> Result<Map<String, Value>, Error> {
+    let columns = UserColumn::ALL
+        .iter()
+        .copied()
+        .filter(|column| policy.can_read(column.field()))
+        .collect::<Vec<_>>();
+
+    let mut user = fetch_columns(pool, internal_id, &columns, options).await?;
+    if let Some(bio) = user.remove("bio") {
+        user.insert("profile.bio".to_owned(), bio);
+    }
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn forbidden_fields() {
+        let policy = FieldAccess::default().read(&["two"]).write(&["one"]);
+        let patch = serde_json::from_value::<Update>(json!({
+            "one": "1",
+            "two": null,
+            "profile": { "bio": "hi" },
+        }))
+        .unwrap();
+
+        match patch.check_writable(&policy) {
+            Err(Error::Forbidden(fields)) => assert_eq!(fields, vec!["two", "profile.bio"]),
+            other => panic!("expected forbidden fields, got {:?}", other),
+        }
+
+        let patch = serde_json::from_value::<Update>(json!({ "one": null })).unwrap();
+        patch.check_writable(&policy).unwrap();
+    }
+}
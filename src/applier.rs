@@ -0,0 +1,479 @@
+//! Applying a stream of patches in batches from a background task.
+
+use crate::{
+    batch::{insert_or_update_many, BatchMode},
+    DbPool, Error, InternalId, Options, Update,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Notify, Semaphore},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// How the background applier batches patches.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ApplierConfig {
+    /// Patches that may be queued before `Applier::send` waits. Defaults to 1024.
+    pub(crate) capacity: usize,
+    /// Most patches collected into one batch. Defaults to 100.
+    pub(crate) max_batch: usize,
+    /// How long to wait for more patches after the first one of a batch. Defaults to 50ms.
+    pub(crate) window: Duration,
+    pub(crate) mode: BatchMode,
+    /// Most batches applied at the same time, each in its own transaction. Defaults to 1.
+    ///
+    /// A key is never in two batches at once so patches to it still apply in order.
+    pub(crate) max_concurrent: usize,
+    /// Shortest time between writes to the same key, `None` to write as fast as possible.
+    ///
+    /// Patches to a key written more recently than this are held back and combined with later
+    /// patches to it, so a hot row is written at a bounded rate while other keys carry on. For
+    /// example 100ms allows 10 writes per second per key.
+    pub(crate) min_key_interval: Option<Duration>,
+}
+
+impl Default for ApplierConfig {
+    fn default() -> Self {
+        ApplierConfig {
+            capacity: 1024,
+            max_batch: 100,
+            window: Duration::from_millis(50),
+            mode: BatchMode::Continue,
+            max_concurrent: 1,
+            min_key_interval: None,
+        }
+    }
+}
+
+/// What the applier did before it stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ApplierReport {
+    /// Patches sent to the applier.
+    pub(crate) received: usize,
+    /// Patches combined with an earlier patch for the same key that hadn't been written yet.
+    pub(crate) combined: usize,
+    /// Patches dropped because the patch already queued for the key set the same fields to the
+    /// same values, so applying them would change nothing.
+    pub(crate) coalesced: usize,
+    /// Rows written successfully, `Outcome::Noop` included.
+    pub(crate) applied: usize,
+    /// Rows whose patch failed.
+    pub(crate) failed: usize,
+    pub(crate) batches: usize,
+}
+
+/// The applier has stopped so the patch wasn't queued.
+#[derive(Debug)]
+pub(crate) struct Closed(pub(crate) InternalId, pub(crate) Update);
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "applier is closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// Sends patches to a background task that applies them with `insert_or_update_many`.
+///
+/// Patches for the same key that arrive within one batch are combined with `Update::then` so
+/// the row is written once, and repeats of a patch that is still queued are dropped. The task
+/// stops when every `Applier` has been dropped and the queue is drained, or when it is shut down
+/// with `ApplierHandle::shutdown`.
+#[derive(Debug, Clone)]
+pub(crate) struct Applier {
+    sender: mpsc::Sender<(InternalId, Update)>,
+}
+
+impl Applier {
+    /// Fails with `Error::InvalidConfig` if `Options::idempotency_key` is set, since every patch
+    /// would share it, or if the options name a field patches don't have.
+    pub(crate) fn spawn(
+        pool: DbPool,
+        options: Arc<Options>,
+        config: ApplierConfig,
+    ) -> Result<(Self, ApplierHandle), Error> {
+        if options.idempotency_key.is_some() {
+            return Err(Error::InvalidConfig("idempotency_key"));
+        }
+        options.check_fields()?;
+
+        let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+        let (shutdown, signal) = oneshot::channel();
+        let task = tokio::spawn(run(receiver, signal, pool, options, config));
+        Ok((Applier { sender }, ApplierHandle { shutdown, task }))
+    }
+
+    /// Queue a patch, waiting for room if the queue is full.
+    pub(crate) async fn send(&self, internal_id: InternalId, patch: Update) -> Result<(), Closed> {
+        self.sender
+            .send((internal_id, patch))
+            .await
+            .map_err(|mpsc::error::SendError((internal_id, patch))| Closed(internal_id, patch))
+    }
+}
+
+/// What the applier did, and the patches it didn't get to before its deadline.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    pub(crate) report: ApplierReport,
+    /// Patches that were queued or held back but never written, combined per key. Nothing else
+    /// has them so store them somewhere to apply later.
+    ///
+    /// Includes patches in batches that failed, and those that failed on their own with an
+    /// error `Options::retry` considers retryable. Other failures are only counted in
+    /// `ApplierReport::failed` since applying them again would fail the same way.
+    pub(crate) unapplied: Vec<(InternalId, Update)>,
+}
+
+/// Stops the applier and waits for it.
+#[derive(Debug)]
+pub(crate) struct ApplierHandle {
+    shutdown: oneshot::Sender<Instant>,
+    task: JoinHandle<Shutdown>,
+}
+
+impl ApplierHandle {
+    /// Stop accepting patches, so `Applier::send` fails with `Closed`, and apply what is queued.
+    ///
+    /// No batch is started after `deadline` and whatever hasn't been written by then is returned
+    /// in `Shutdown::unapplied`. Batches already running are waited for even past the deadline,
+    /// since cancelling one couldn't tell whether it was committed.
+    pub(crate) async fn shutdown(self, deadline: Duration) -> Shutdown {
+        // fails if the applier already stopped, in which case there is nothing to signal
+        let _ = self.shutdown.send(Instant::now() + deadline);
+        join(self.task).await
+    }
+
+    /// Wait for the applier to stop on its own, once every `Applier` has been dropped and the
+    /// queue is drained.
+    pub(crate) async fn join(self) -> Shutdown {
+        join(self.task).await
+    }
+}
+
+async fn join(task: JoinHandle<Shutdown>) -> Shutdown {
+    match task.await {
+        Ok(shutdown) => shutdown,
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+async fn run(
+    mut receiver: mpsc::Receiver<(InternalId, Update)>,
+    mut signal: oneshot::Receiver<Instant>,
+    pool: DbPool,
+    options: Arc<Options>,
+    config: ApplierConfig,
+) -> Shutdown {
+    let report = Arc::new(Mutex::new(ApplierReport::default()));
+    // patches from failed batches, to hand back in `Shutdown::unapplied`
+    let failed = Arc::new(Mutex::new(Batch::default()));
+    let max_concurrent = config.max_concurrent.max(1);
+    let permits = Arc::new(Semaphore::new(max_concurrent));
+    let mut limiter = KeyLimiter::new(config.min_key_interval);
+    // patches held back by `limiter`, the start of the next batch
+    let mut held = Batch::default();
+    let mut closed = false;
+    // set once shutdown is requested
+    let mut deadline = None;
+    let mut signalled = false;
+
+    let mut unapplied = loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break std::mem::take(&mut held);
+        }
+
+        let mut batch = std::mem::take(&mut held);
+        if batch.is_empty() {
+            tokio::select! {
+                patch = receiver.recv() => match patch {
+                    Some(patch) => batch.push(patch, &mut report.lock().unwrap()),
+                    None => break Batch::default(),
+                },
+                at = &mut signal, if !signalled => {
+                    signalled = true;
+                    shut_down(at, &mut deadline, &mut receiver);
+                    continue;
+                }
+            }
+        }
+
+        // once closed by a shutdown `recv` returns straight away so the window isn't waited for
+        let window = Instant::now() + config.window;
+        while !closed && batch.received < config.max_batch {
+            tokio::select! {
+                patch = time::timeout_at(window, receiver.recv()) => match patch {
+                    Ok(Some(patch)) => batch.push(patch, &mut report.lock().unwrap()),
+                    Ok(None) => closed = true,
+                    Err(_) => break,
+                },
+                at = &mut signal, if !signalled => {
+                    signalled = true;
+                    shut_down(at, &mut deadline, &mut receiver);
+                }
+            }
+        }
+
+        let (ready, rest) = limiter.split(batch);
+        held = rest;
+
+        if !ready.is_empty() {
+            let permit = match deadline {
+                Some(deadline) => {
+                    match time::timeout_at(deadline, Arc::clone(&permits).acquire_owned()).await {
+                        Ok(permit) => permit.unwrap(),
+                        Err(_) => break ready.then(held),
+                    }
+                }
+                None => Arc::clone(&permits).acquire_owned().await.unwrap(),
+            };
+            let pool = pool.clone();
+            let options = Arc::clone(&options);
+            let report = Arc::clone(&report);
+            let failed = Arc::clone(&failed);
+            let in_flight = Arc::clone(&limiter.in_flight);
+            let finished = Arc::clone(&limiter.finished);
+            tokio::spawn(async move {
+                let keys = ready.positions.keys().copied().collect::<Vec<_>>();
+                let unapplied = flush(&pool, &options, config.mode, ready.patches, &report).await;
+                if !unapplied.is_empty() {
+                    let mut failed = failed.lock().unwrap();
+                    *failed = std::mem::take(&mut *failed).then(Batch::from(unapplied));
+                }
+                {
+                    let mut in_flight = in_flight.lock().unwrap();
+                    for key in keys {
+                        in_flight.remove(&key);
+                    }
+                }
+                finished.notify_one();
+                drop(permit);
+            });
+        }
+
+        if closed {
+            if held.is_empty() {
+                break Batch::default();
+            }
+            // nothing more will arrive so wait for the held back keys instead of the window, either
+            // for their interval to pass or for a batch they are in to finish
+            let wake = limiter.next_ready(&held).into_iter().chain(deadline).min();
+            tokio::select! {
+                _ = limiter.finished.notified() => {}
+                _ = sleep_until(wake) => {}
+            }
+        }
+    };
+
+    // past the deadline, so whatever is still queued won't be applied either
+    while let Ok(patch) = receiver.try_recv() {
+        unapplied.push(patch, &mut report.lock().unwrap());
+    }
+
+    // every permit being free means every batch has been applied
+    for _ in 0..max_concurrent {
+        permits.acquire().await.unwrap().forget();
+    }
+
+    // failed batches came before whatever is left, so their patches go first
+    let failed = std::mem::take(&mut *failed.lock().unwrap());
+    let report = *report.lock().unwrap();
+    Shutdown {
+        report,
+        unapplied: failed.then(unapplied).patches,
+    }
+}
+
+/// Sleep until `at`, or forever if it is `None`.
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Stop accepting patches, while those already queued can still be received.
+fn shut_down(
+    at: Result<Instant, oneshot::error::RecvError>,
+    deadline: &mut Option<Instant>,
+    receiver: &mut mpsc::Receiver<(InternalId, Update)>,
+) {
+    // an error means the handle was dropped without shutting down, so carry on as before
+    if let Ok(at) = at {
+        *deadline = Some(at);
+        receiver.close();
+    }
+}
+
+#[derive(Default)]
+struct Batch {
+    patches: Vec<(InternalId, Update)>,
+    positions: HashMap<InternalId, usize>,
+    received: usize,
+}
+
+impl Batch {
+    fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    fn push(&mut self, (internal_id, patch): (InternalId, Update), report: &mut ApplierReport) {
+        self.received += 1;
+        report.received += 1;
+
+        match self.positions.get(&internal_id) {
+            Some(&position) => {
+                let earlier = std::mem::take(&mut self.patches[position].1);
+                let combined = earlier.clone().then(patch);
+                if combined == earlier {
+                    report.coalesced += 1;
+                } else {
+                    report.combined += 1;
+                }
+                self.patches[position].1 = combined;
+            }
+            None => self.insert(internal_id, patch),
+        }
+    }
+
+    fn insert(&mut self, internal_id: InternalId, patch: Update) {
+        self.positions.insert(internal_id, self.patches.len());
+        self.patches.push((internal_id, patch));
+    }
+
+    /// Combine with patches that came after these, for keys in both.
+    fn then(mut self, later: Batch) -> Batch {
+        for (internal_id, patch) in later.patches {
+            match self.positions.get(&internal_id) {
+                Some(&position) => {
+                    let earlier = std::mem::take(&mut self.patches[position].1);
+                    self.patches[position].1 = earlier.then(patch);
+                }
+                None => self.insert(internal_id, patch),
+            }
+        }
+        self.received = self.patches.len();
+        self
+    }
+}
+
+/// Patches in the order given, assuming each key appears once.
+impl From<Vec<(InternalId, Update)>> for Batch {
+    fn from(patches: Vec<(InternalId, Update)>) -> Self {
+        let mut batch = Batch::default();
+        for (internal_id, patch) in patches {
+            batch.insert(internal_id, patch);
+        }
+        batch.received = batch.patches.len();
+        batch
+    }
+}
+
+/// Decides which keys may be written now.
+struct KeyLimiter {
+    min_interval: Option<Duration>,
+    last_written: HashMap<InternalId, Instant>,
+    /// Keys in a batch that hasn't finished yet.
+    in_flight: Arc<Mutex<HashSet<InternalId>>>,
+    /// Notified whenever a batch finishes, so its keys are no longer in flight.
+    finished: Arc<Notify>,
+}
+
+impl KeyLimiter {
+    fn new(min_interval: Option<Duration>) -> Self {
+        KeyLimiter {
+            min_interval,
+            last_written: HashMap::new(),
+            in_flight: Arc::default(),
+            finished: Arc::default(),
+        }
+    }
+
+    /// Split into the patches that can be written now and those that must wait.
+    fn split(&mut self, batch: Batch) -> (Batch, Batch) {
+        let now = Instant::now();
+        if let Some(min_interval) = self.min_interval {
+            self.last_written
+                .retain(|_, written| now.duration_since(*written) < min_interval);
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let mut ready = Batch::default();
+        let mut rest = Batch::default();
+        for (internal_id, patch) in batch.patches {
+            if in_flight.contains(&internal_id) || self.last_written.contains_key(&internal_id) {
+                rest.insert(internal_id, patch);
+            } else {
+                in_flight.insert(internal_id);
+                if self.min_interval.is_some() {
+                    self.last_written.insert(internal_id, now);
+                }
+                ready.insert(internal_id, patch);
+            }
+        }
+        ready.received = ready.patches.len();
+        rest.received = rest.patches.len();
+        (ready, rest)
+    }
+
+    /// When the first of the held back keys is past `min_interval`, assuming in flight batches
+    /// finish by then. `None` if they are only held back for being in flight, in which case wait
+    /// for `finished` instead.
+    fn next_ready(&self, held: &Batch) -> Option<Instant> {
+        let min_interval = self.min_interval.unwrap_or_default();
+        held.patches
+            .iter()
+            .filter_map(|(internal_id, _)| self.last_written.get(internal_id))
+            .map(|written| *written + min_interval)
+            .min()
+    }
+}
+
+/// Apply a batch, returning the patches that should be applied again later.
+async fn flush(
+    pool: &DbPool,
+    options: &Options,
+    mode: BatchMode,
+    patches: Vec<(InternalId, Update)>,
+    report: &Mutex<ApplierReport>,
+) -> Vec<(InternalId, Update)> {
+    let results = insert_or_update_many(pool, &patches, options, mode).await;
+
+    let mut report = report.lock().unwrap();
+    report.batches += 1;
+    match results {
+        Ok(results) => {
+            let mut unapplied = Vec::new();
+            for ((internal_id, patch), result) in patches.into_iter().zip(results) {
+                match result {
+                    Ok(_) => report.applied += 1,
+                    Err(err) => {
+                        report.failed += 1;
+                        tracing::warn!(
+                            internal_id = internal_id.0,
+                            patch = ?patch.redacted(&options.redact),
+                            %err,
+                            "patch failed",
+                        );
+                        if options.retry.is_retryable(&err) {
+                            unapplied.push((internal_id, patch));
+                        }
+                    }
+                }
+            }
+            unapplied
+        }
+        Err(err) => {
+            report.failed += patches.len();
+            tracing::warn!(patches = patches.len(), %err, "batch failed");
+            patches
+        }
+    }
+}
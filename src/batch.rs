@@ -0,0 +1,113 @@
+use crate::{breaker, diff, retry, Applied, DbPool, Error, InternalId, Options, Outcome, Update};
+
+/// What `insert_or_update_many` does when one of the patches fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BatchMode {
+    /// Roll back the whole batch and return the error.
+    Abort,
+    /// Roll back only the failing patch and carry on with the rest. Intended for bulk ingestion
+    /// where one bad row shouldn't hold up the others.
+    Continue,
+}
+
+/// Apply several patches in one transaction, returning the result of each in order.
+///
+/// With `BatchMode::Continue` each patch runs in a savepoint so a failing one is rolled back on
+/// its own. Errors `Options::retry` considers retryable, by default serialization failures and
+/// deadlocks, still retry the whole batch in both modes since the transaction can't carry on after
+/// them.
+///
+/// Fails with `Error::InvalidConfig` if `Options::idempotency_key` is set, since every patch would
+/// share it, or if the options name a field patches don't have.
+pub(crate) async fn insert_or_update_many(
+    pool: &DbPool,
+    patches: &[(InternalId, Update)],
+    options: &Options,
+    mode: BatchMode,
+) -> Result<Vec<Result<Outcome, Error>>, Error> {
+    if options.idempotency_key.is_some() {
+        return Err(Error::InvalidConfig("idempotency_key"));
+    }
+    options.check_fields()?;
+
+    retry::retrying(options.retry.as_ref(), || {
+        breaker::call(
+            options.breaker.as_deref(),
+            try_insert_or_update_many(pool, patches, options, mode),
+        )
+    })
+    .await
+}
+
+async fn try_insert_or_update_many(
+    pool: &DbPool,
+    patches: &[(InternalId, Update)],
+    options: &Options,
+    mode: BatchMode,
+) -> Result<Vec<Result<Outcome, Error>>, Error> {
+    if let Some(explain) = &options.explain {
+        explain.clear();
+    }
+
+    let mut con = pool.get().await?;
+    let mut tx = con
+        .build_transaction()
+        .isolation_level(options.isolation_level)
+        .start()
+        .await?;
+    crate::apply_session(&tx, options).await?;
+
+    let mut results = Vec::with_capacity(patches.len());
+    let mut applieds = Vec::with_capacity(patches.len());
+    for (internal_id, patch) in patches {
+        let mut applied = Applied::default();
+        let result = match mode {
+            BatchMode::Abort => {
+                patch
+                    .apply_in_transaction(*internal_id, &tx, options, &mut applied)
+                    .await
+            }
+            BatchMode::Continue => {
+                let savepoint = tx.savepoint("batch_patch").await?;
+                let result = patch
+                    .apply_in_transaction(*internal_id, &savepoint, options, &mut applied)
+                    .await;
+                if result.is_ok() {
+                    savepoint.commit().await?;
+                } else {
+                    savepoint.rollback().await?;
+                }
+                result
+            }
+        };
+
+        match result {
+            Err(err) if mode == BatchMode::Abort || options.retry.is_retryable(&err) => {
+                return Err(err)
+            }
+            result => {
+                results.push(result);
+                applieds.push(applied);
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    for (((internal_id, patch), result), applied) in patches.iter().zip(&results).zip(&applieds) {
+        if let Ok(outcome) = result {
+            if let Some(cache) = &options.cache {
+                cache.invalidate(*internal_id);
+            }
+            diff::emit(
+                *internal_id,
+                outcome,
+                applied.before.as_ref(),
+                applied.patch.as_ref().unwrap_or(patch),
+                &options.redact,
+            );
+        }
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,42 @@
+//! Apply a JSON patch read from stdin.
+//!
+//! ```text
+//! echo '{ "one": "1", "two": null }' | upsert-sql users 1
+//! ```
+//!
+//! Connects using `DATABASE_URL` or the standard `PG*` variables.
+
+use std::{env, io::Read, process};
+use upsert_sql::{cli, PoolConfig};
+
+#[tokio::main]
+async fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (table, internal_id) = match args.as_slice() {
+        [table, internal_id] => match internal_id.parse::<i64>() {
+            Ok(internal_id) => (table, internal_id),
+            Err(_) => exit(format!("invalid key `{}`", internal_id)),
+        },
+        _ => exit("usage: upsert-sql <table> <key> < patch.json"),
+    };
+
+    let mut patch = String::new();
+    if let Err(err) = std::io::stdin().read_to_string(&mut patch) {
+        exit(format!("failed to read patch: {}", err));
+    }
+
+    let result = async {
+        let pool = PoolConfig::from_env()?.build().await?;
+        cli::apply(&pool, table, internal_id, &patch).await
+    };
+
+    match result.await {
+        Ok(outcome) => println!("{}", outcome),
+        Err(err) => exit(err),
+    }
+}
+
+fn exit(message: impl std::fmt::Display) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1)
+}
@@ -0,0 +1,191 @@
+//! Failing fast while the database is down.
+//!
+//! Without a breaker every patch waits for a connection until the pool times out, so requests pile
+//! up behind a database that isn't there. After `failure_threshold` consecutive connection
+//! failures the breaker opens and patches fail with `Error::Unavailable` straight away. After
+//! `reset_after` one patch is let through as a probe: if it succeeds the breaker closes, otherwise
+//! it stays open for another `reset_after`.
+
+use crate::Error;
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    failure_threshold: usize,
+    reset_after: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe is running, everything else fails fast until it finishes.
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: usize, reset_after: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            reset_after,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether the breaker is currently failing patches fast.
+    pub(crate) fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), State::Closed { .. })
+    }
+
+    /// Run `operation` unless the breaker is open, recording whether it reached the database.
+    pub(crate) async fn call<T, F>(&self, operation: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let attempt = self.acquire()?;
+        let result = operation.await;
+        let success = !matches!(&result, Err(err) if is_failure(err));
+        attempt.finish(self, success);
+        result
+    }
+
+    fn acquire(&self) -> Result<Attempt<'_>, Error> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(Attempt { probe: None }),
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                Ok(Attempt { probe: Some(self) })
+            }
+            State::Open { .. } | State::HalfOpen => Err(Error::Unavailable),
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            _ if success => State::Closed { failures: 0 },
+            State::Closed { failures } if failures + 1 < self.failure_threshold => State::Closed {
+                failures: failures + 1,
+            },
+            _ => State::Open {
+                until: Instant::now() + self.reset_after,
+            },
+        };
+    }
+}
+
+/// A call let through the breaker.
+struct Attempt<'a> {
+    /// Set if this is the half open probe, so dropping it before it finishes reopens the breaker
+    /// rather than leaving it half open forever.
+    probe: Option<&'a CircuitBreaker>,
+}
+
+impl Attempt<'_> {
+    fn finish(mut self, breaker: &CircuitBreaker, success: bool) {
+        self.probe = None;
+        breaker.record(success);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if let Some(breaker) = self.probe {
+            breaker.record(false);
+        }
+    }
+}
+
+/// Only errors getting or using a connection count, a patch that is rejected still reached the
+/// database.
+fn is_failure(err: &Error) -> bool {
+    match err {
+        Error::Pool(_) | Error::Timeout => true,
+        // errors reported by postgres have a code, I/O errors and closed connections don't
+        Error::Postgres(err) => err.code().is_none(),
+        _ => false,
+    }
+}
+
+/// `CircuitBreaker::call` if there is a breaker.
+pub(crate) async fn call<T, F>(breaker: Option<&CircuitBreaker>, operation: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    match breaker {
+        Some(breaker) => breaker.call(operation).await,
+        None => operation.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fail(breaker: &CircuitBreaker) -> Result<(), Error> {
+        breaker.call(async { Err(Error::Timeout) }).await
+    }
+
+    async fn succeed(breaker: &CircuitBreaker) -> Result<(), Error> {
+        breaker.call(async { Ok(()) }).await
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+
+        fail(&breaker).await.unwrap_err();
+        succeed(&breaker).await.unwrap();
+        fail(&breaker).await.unwrap_err();
+        assert!(!breaker.is_open());
+
+        // other errors don't count
+        breaker
+            .call(async { Err::<(), _>(Error::NotFound) })
+            .await
+            .unwrap_err();
+        assert!(!breaker.is_open());
+
+        fail(&breaker).await.unwrap_err();
+        fail(&breaker).await.unwrap_err();
+        assert!(breaker.is_open());
+        assert!(matches!(succeed(&breaker).await, Err(Error::Unavailable)));
+    }
+
+    #[tokio::test]
+    async fn probes_after_reset() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        fail(&breaker).await.unwrap_err();
+        assert!(matches!(succeed(&breaker).await, Err(Error::Unavailable)));
+
+        // a failed probe opens it again
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(matches!(fail(&breaker).await, Err(Error::Timeout)));
+        assert!(matches!(succeed(&breaker).await, Err(Error::Unavailable)));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        succeed(&breaker).await.unwrap();
+        assert!(!breaker.is_open());
+    }
+
+    #[tokio::test]
+    async fn cancelled_probe_reopens() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        fail(&breaker).await.unwrap_err();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let probe = breaker.call(std::future::pending::<Result<(), Error>>());
+        let _ = tokio::time::timeout(Duration::from_millis(10), probe).await;
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{fetch_with_options, DbPool, Error, InternalId, Options, User};
+#[cfg(feature = "moka")]
+use std::time::Duration;
+
+/// Cache of users in front of `fetch`.
+///
+/// Set `Options::cache` so `insert_or_update` invalidates users it writes once the transaction has
+/// committed. `insert_or_update_in_transaction` and `update_where` don't know about caches, so
+/// callers using them must invalidate themselves.
+pub(crate) trait UserCache: Send + Sync {
+    fn get(&self, internal_id: InternalId) -> Option<User>;
+
+    fn insert(&self, user: User);
+
+    fn invalidate(&self, internal_id: InternalId);
+}
+
+/// Like `fetch_with_options` but consults `cache` first and fills it on misses.
+///
+/// Users are cached decrypted, as `options` reads them. The cache isn't keyed by tenant, so cached
+/// users outside `options.tenant` aren't found, like they aren't in the database.
+pub(crate) async fn fetch_cached(
+    pool: &DbPool,
+    cache: &dyn UserCache,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<User, Error> {
+    if let Some(user) = cache.get(internal_id) {
+        if options.tenant.is_some() && user.organization_id != options.tenant {
+            return Err(Error::NotFound);
+        }
+        return Ok(user);
+    }
+    let user = fetch_with_options(pool, internal_id, options).await?;
+    cache.insert(user.clone());
+    Ok(user)
+}
+
+/// `UserCache` backed by a bounded moka cache.
+///
+/// Users expire `time_to_live` after they're cached. Invalidation runs after commit, so a
+/// `fetch_cached` that read the row before the commit can cache the old user after it has been
+/// invalidated. Expiry bounds how long that stale user is served.
+#[cfg(feature = "moka")]
+pub(crate) struct MokaCache(moka::sync::Cache<InternalId, User>);
+
+#[cfg(feature = "moka")]
+impl MokaCache {
+    pub(crate) fn new(max_capacity: u64, time_to_live: Duration) -> Self {
+        MokaCache(
+            moka::sync::Cache::builder()
+                .max_capacity(max_capacity)
+                .time_to_live(time_to_live)
+                .build(),
+        )
+    }
+}
+
+#[cfg(feature = "moka")]
+impl UserCache for MokaCache {
+    fn get(&self, internal_id: InternalId) -> Option<User> {
+        self.0.get(&internal_id)
+    }
+
+    fn insert(&self, user: User) {
+        self.0.insert(user.internal_id, user);
+    }
+
+    fn invalidate(&self, internal_id: InternalId) {
+        self.0.invalidate(&internal_id);
+    }
+}
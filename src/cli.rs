@@ -0,0 +1,27 @@
+use crate::{DbPool, Error, InternalId, Outcome, PatchLimits, Update};
+
+/// Apply a JSON patch to the row with `internal_id` in `table` and describe what happened.
+///
+/// Only the `users` table is supported.
+pub async fn apply(
+    pool: &DbPool,
+    table: &str,
+    internal_id: i64,
+    patch: &str,
+) -> Result<String, Error> {
+    if table != "users" {
+        return Err(Error::InvalidConfig("table"));
+    }
+
+    let patch = Update::from_json_with_limits(patch, &PatchLimits::default())?;
+
+    let outcome = match patch
+        .insert_or_update(InternalId(internal_id), pool)
+        .await?
+    {
+        Outcome::Inserted { .. } => "inserted".to_owned(),
+        Outcome::Updated { changed_fields } => format!("updated {}", changed_fields.join(", ")),
+        Outcome::Noop => "unchanged".to_owned(),
+    };
+    Ok(outcome)
+}
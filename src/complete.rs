@@ -0,0 +1,195 @@
+use crate::{ProfileUpdate, Update, UserStatus, DEFAULT_LOCALE};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::{convert::TryFrom, fmt};
+
+/// A user with every writable field set, built from a patch used to create one.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NewUser {
+    pub(crate) one: Option<String>,
+    pub(crate) two: Option<String>,
+    pub(crate) metadata: Option<Value>,
+    pub(crate) status: Option<UserStatus>,
+    pub(crate) locale: String,
+    pub(crate) organization_id: Option<i64>,
+    pub(crate) bio: Option<String>,
+}
+
+/// Fields a patch must contain to create a user, possibly as `null`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingFields(pub Vec<&'static str>);
+
+impl fmt::Display for MissingFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "missing fields: {}", self.0.join(", "))
+    }
+}
+
+impl std::error::Error for MissingFields {}
+
+/// Fields with a column default, `status` and `locale`, may be missing and get the same default as
+/// `insert_or_update` would give them. All other fields must be present.
+impl TryFrom<Update> for NewUser {
+    type Error = MissingFields;
+
+    fn try_from(update: Update) -> Result<Self, Self::Error> {
+        let mut missing = Vec::new();
+        let bio = update.profile.and_then(|profile| profile.bio);
+
+        let one = required("one", update.one, &mut missing);
+        let two = required("two", update.two, &mut missing);
+        let metadata = required("metadata", update.metadata, &mut missing);
+        let organization_id = required("organization_id", update.organization_id, &mut missing);
+        let bio = required("profile.bio", bio, &mut missing);
+
+        if !missing.is_empty() {
+            return Err(MissingFields(missing));
+        }
+
+        Ok(NewUser {
+            one,
+            two,
+            metadata,
+            status: update.status.unwrap_or(Some(UserStatus::Active)),
+            locale: update
+                .locale
+                .flatten()
+                .unwrap_or_else(|| DEFAULT_LOCALE.to_owned()),
+            organization_id,
+            bio,
+        })
+    }
+}
+
+/// A full replacement of a user, as sent with `PUT`.
+///
+/// Deserializes like `Update` but fields that are missing are reset, to the same defaults a new
+/// user gets, instead of left untouched. Apply it with `Replace::into_patch` so it goes through the
+/// same validation, SQL, and outcome reporting as a patch.
+#[derive(Debug, Clone)]
+pub(crate) struct Replace(Update);
+
+impl Replace {
+    pub(crate) fn into_patch(self) -> Update {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Replace {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let update = Update::deserialize(deserializer)?;
+        let profile = update.profile.unwrap_or_default();
+        Ok(Replace(Update {
+            one: Some(update.one.flatten()),
+            two: Some(update.two.flatten()),
+            metadata: Some(update.metadata.flatten()),
+            status: Some(update.status.unwrap_or(Some(UserStatus::Active))),
+            // `null` resets to the column default
+            locale: Some(update.locale.flatten()),
+            organization_id: Some(update.organization_id.flatten()),
+            profile: Some(ProfileUpdate {
+                bio: Some(profile.bio.flatten()),
+            }),
+        }))
+    }
+}
+
+fn required<T>(
+    field: &'static str,
+    patch: Option<Option<T>>,
+    missing: &mut Vec<&'static str>,
+) -> Option<T> {
+    patch.unwrap_or_else(|| {
+        missing.push(field);
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_patch_eq, assert_present};
+    use serde_json::json;
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn complete() {
+        let user = NewUser::try_from(update(json!({
+            "one": "1",
+            "two": null,
+            "metadata": null,
+            "organization_id": 1,
+            "profile": { "bio": "hi" },
+        })))
+        .unwrap();
+
+        assert_eq!(
+            user,
+            NewUser {
+                one: Some("1".to_owned()),
+                two: None,
+                metadata: None,
+                status: Some(UserStatus::Active),
+                locale: "en".to_owned(),
+                organization_id: Some(1),
+                bio: Some("hi".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn replace_resets_missing_fields() {
+        let patch = serde_json::from_value::<Replace>(json!({ "one": "1", "status": null }))
+            .unwrap()
+            .into_patch();
+
+        assert_present!(
+            patch,
+            [
+                "one",
+                "two",
+                "metadata",
+                "status",
+                "locale",
+                "organization_id",
+                "profile.bio"
+            ]
+        );
+        assert_patch_eq!(
+            patch,
+            update(json!({
+                "one": "1",
+                "two": null,
+                "metadata": null,
+                "status": null,
+                "locale": null,
+                "organization_id": null,
+                "profile": { "bio": null },
+            })),
+        );
+
+        let patch = serde_json::from_value::<Replace>(json!({}))
+            .unwrap()
+            .into_patch();
+        assert_eq!(patch.status, Some(Some(UserStatus::Active)));
+    }
+
+    #[test]
+    fn missing() {
+        let err = NewUser::try_from(update(json!({ "one": "1", "metadata": null }))).unwrap_err();
+        assert_eq!(
+            err,
+            MissingFields(vec!["two", "organization_id", "profile.bio"])
+        );
+        assert_eq!(
+            err.to_string(),
+            "missing fields: two, organization_id, profile.bio"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{
+    redact::{REDACTED, SENSITIVE},
+    store, InternalId, Outcome, Update, User,
+};
+use serde_json::{json, Map, Value};
+
+/// Emit a `tracing` event describing the changes an applied patch made.
+///
+/// The event has the key, the outcome, and `changes`, a JSON object mapping each changed field to
+/// its `old` and `new` value. Values of fields in `redact`, and always `SENSITIVE`, are replaced
+/// with `"***"`.
+pub(crate) fn emit(
+    internal_id: InternalId,
+    outcome: &Outcome,
+    before: Option<&User>,
+    patch: &Update,
+    redact: &[&str],
+) {
+    let changes = Value::Object(changes(internal_id, outcome, before, patch, redact));
+    let outcome = match outcome {
+        Outcome::Inserted { .. } => "inserted",
+        Outcome::Updated { .. } => "updated",
+        Outcome::Noop => return,
+    };
+    tracing::info!(
+        target: "upsert_sql::patch",
+        internal_id = internal_id.0,
+        outcome,
+        %changes,
+        "patch applied",
+    );
+}
+
+pub(crate) fn changes(
+    internal_id: InternalId,
+    outcome: &Outcome,
+    before: Option<&User>,
+    patch: &Update,
+    redact: &[&str],
+) -> Map<String, Value> {
+    // inserted rows are compared against the defaults they were inserted with
+    let before = before
+        .cloned()
+        .unwrap_or_else(|| User::with_defaults(0, internal_id));
+    let mut after = before.clone();
+    after.apply(patch);
+
+    let fields = match outcome {
+        Outcome::Inserted { .. } => store::changed_fields(&before, &after),
+        // the patch might not have been applied in full, such as with `ConflictPolicy::Merge`
+        Outcome::Updated { changed_fields } => changed_fields.clone(),
+        Outcome::Noop => Vec::new(),
+    };
+    // only set when resurrecting with `DeletedPolicy::Resurrect`
+    if fields.contains(&"deleted_at") {
+        after.deleted_at = None;
+    }
+
+    let before = to_map(&before);
+    let after = to_map(&after);
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let column = field.trim_start_matches("profile.");
+            let (old, new) = (before.get(column)?, after.get(column)?);
+            let change = if redact.contains(&field) || SENSITIVE.contains(&field) {
+                json!({ "old": REDACTED, "new": REDACTED })
+            } else {
+                json!({ "old": old, "new": new })
+            };
+            Some((field.to_owned(), change))
+        })
+        .collect()
+}
+
+fn to_map(user: &User) -> Map<String, Value> {
+    match serde_json::to_value(user) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn updated() {
+        let mut before = User::with_defaults(1, InternalId(1));
+        before.one = Some("1".to_owned());
+
+        let outcome = Outcome::Updated {
+            changed_fields: vec!["one", "status", "locale"],
+        };
+        let patch = update(json!({
+            "one": "2",
+            "two": null,
+            "status": "suspended",
+            "locale": "da",
+        }));
+        let changes = changes(InternalId(1), &outcome, Some(&before), &patch, &["locale"]);
+
+        assert_eq!(
+            Value::Object(changes),
+            json!({
+                "one": { "old": "***", "new": "***" },
+                "status": { "old": "active", "new": "suspended" },
+                "locale": { "old": "***", "new": "***" },
+            })
+        );
+    }
+
+    #[test]
+    fn inserted() {
+        let patch = update(json!({ "one": "1", "two": null, "locale": "da" }));
+        let changes = changes(
+            InternalId(1),
+            &Outcome::Inserted { id: 1 },
+            None,
+            &patch,
+            &[],
+        );
+
+        // sensitive fields are redacted without being listed in `Options::redact`
+        assert_eq!(
+            Value::Object(changes),
+            json!({
+                "one": { "old": "***", "new": "***" },
+                "locale": { "old": "en", "new": "da" },
+            })
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Encrypting text fields before they are written and decrypting them when read.
+//!
+//! Encrypted values are stored as `<key id>:<base64 ciphertext>` so a value can still be read
+//! after the field moves to a new key. The `users.search` column is built from the stored values,
+//! so encrypted fields can't be searched.
+
+use crate::{patched, Error, Update, User};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{fmt, sync::Arc};
+
+pub(crate) type KeyringError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Encrypts and decrypts with named keys, for example AES-GCM with keys from a KMS.
+///
+/// Encryption should be randomized so equal values don't have equal ciphertexts. Changes are
+/// detected by comparing decrypted values.
+pub(crate) trait Keyring: Send + Sync {
+    fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, KeyringError>;
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>, KeyringError>;
+}
+
+/// Which fields are encrypted and with which key.
+///
+/// `one`, `two`, and `profile.bio` can be encrypted. Reads given the same `Options`, such as
+/// `fetch_with_options`, decrypt them.
+#[derive(Clone)]
+pub(crate) struct Encryption {
+    keyring: Arc<dyn Keyring>,
+    fields: Vec<(&'static str, &'static str)>,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
+
+impl Encryption {
+    pub(crate) fn new(keyring: Arc<dyn Keyring>) -> Self {
+        Encryption {
+            keyring,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Encrypt `field` with the key `key_id`.
+    pub(crate) fn field(mut self, field: &'static str, key_id: &'static str) -> Self {
+        self.fields.push((field, key_id));
+        self
+    }
+
+    fn key_id(&self, field: &str) -> Option<&'static str> {
+        self.fields
+            .iter()
+            .find(|(name, _)| *name == field)
+            .map(|(_, key_id)| *key_id)
+    }
+
+    pub(crate) fn encrypt(
+        &self,
+        field: &str,
+        value: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        match (self.key_id(field), value) {
+            (Some(key_id), Some(value)) => {
+                let ciphertext = self
+                    .keyring
+                    .encrypt(key_id, value.as_bytes())
+                    .map_err(Error::Encryption)?;
+                Ok(Some(format!("{}:{}", key_id, STANDARD.encode(ciphertext))))
+            }
+            (_, value) => Ok(value),
+        }
+    }
+
+    pub(crate) fn decrypt(
+        &self,
+        field: &str,
+        value: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        match (self.key_id(field), value) {
+            (Some(_), Some(value)) => {
+                let (key_id, ciphertext) = value.split_once(':').ok_or_else(|| {
+                    Error::Encryption(format!("`{}` isn't encrypted", field).into())
+                })?;
+                let ciphertext = STANDARD
+                    .decode(ciphertext)
+                    .map_err(|err| Error::Encryption(err.into()))?;
+                let plaintext = self
+                    .keyring
+                    .decrypt(key_id, &ciphertext)
+                    .map_err(Error::Encryption)?;
+                String::from_utf8(plaintext)
+                    .map(Some)
+                    .map_err(|err| Error::Encryption(err.into()))
+            }
+            (_, value) => Ok(value),
+        }
+    }
+
+    /// Like `patched` but compares with the decrypted current value and returns the value to
+    /// store, so rewriting a field with its current value isn't a change.
+    pub(crate) fn patched(
+        &self,
+        patch: &Option<Option<String>>,
+        current: Option<String>,
+        field: &'static str,
+        changed_fields: &mut Vec<&'static str>,
+    ) -> Result<Option<String>, Error> {
+        if self.key_id(field).is_none() {
+            return Ok(patched(patch, current, field, changed_fields));
+        }
+
+        match patch {
+            Some(value) if *value != self.decrypt(field, current.clone())? => {
+                changed_fields.push(field);
+                self.encrypt(field, value.clone())
+            }
+            _ => Ok(current),
+        }
+    }
+}
+
+/// Encrypt `value` if `encryption` says `field` is encrypted.
+pub(crate) fn encrypt(
+    encryption: Option<&Encryption>,
+    field: &str,
+    value: Option<String>,
+) -> Result<Option<String>, Error> {
+    match encryption {
+        Some(encryption) => encryption.encrypt(field, value),
+        None => Ok(value),
+    }
+}
+
+/// Decrypt `value` if `encryption` says `field` is encrypted.
+pub(crate) fn decrypt(
+    encryption: Option<&Encryption>,
+    field: &str,
+    value: Option<String>,
+) -> Result<Option<String>, Error> {
+    match encryption {
+        Some(encryption) => encryption.decrypt(field, value),
+        None => Ok(value),
+    }
+}
+
+/// `Encryption::patched`, or plain `patched` without encryption.
+pub(crate) fn patched_text(
+    encryption: Option<&Encryption>,
+    patch: &Option<Option<String>>,
+    current: Option<String>,
+    field: &'static str,
+    changed_fields: &mut Vec<&'static str>,
+) -> Result<Option<String>, Error> {
+    match encryption {
+        Some(encryption) => encryption.patched(patch, current, field, changed_fields),
+        None => Ok(patched(patch, current, field, changed_fields)),
+    }
+}
+
+impl Update {
+    /// The patch as it will be stored, for the patch log.
+    pub(crate) fn encrypted(&self, encryption: &Encryption) -> Result<Update, Error> {
+        let mut patch = self.clone();
+        if let Some(one) = patch.one {
+            patch.one = Some(encryption.encrypt("one", one)?);
+        }
+        if let Some(two) = patch.two {
+            patch.two = Some(encryption.encrypt("two", two)?);
+        }
+        if let Some(profile) = &mut patch.profile {
+            if let Some(bio) = profile.bio.take() {
+                profile.bio = Some(encryption.encrypt("profile.bio", bio)?);
+            }
+        }
+        Ok(patch)
+    }
+
+    /// The patch as it was received, from one read back from the patch log.
+    pub(crate) fn decrypted(&self, encryption: &Encryption) -> Result<Update, Error> {
+        let mut patch = self.clone();
+        if let Some(one) = patch.one {
+            patch.one = Some(encryption.decrypt("one", one)?);
+        }
+        if let Some(two) = patch.two {
+            patch.two = Some(encryption.decrypt("two", two)?);
+        }
+        if let Some(profile) = &mut patch.profile {
+            if let Some(bio) = profile.bio.take() {
+                profile.bio = Some(encryption.decrypt("profile.bio", bio)?);
+            }
+        }
+        Ok(patch)
+    }
+}
+
+impl User {
+    pub(crate) fn decrypted(mut self, encryption: &Encryption) -> Result<User, Error> {
+        self.one = encryption.decrypt("one", self.one)?;
+        self.two = encryption.decrypt("two", self.two)?;
+        self.bio = encryption.decrypt("profile.bio", self.bio)?;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Not encryption, but enough to tell ciphertext from plaintext.
+    pub(crate) struct Reversed;
+
+    impl Keyring for Reversed {
+        fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, KeyringError> {
+            if key_id != "pii" {
+                return Err(format!("unknown key `{}`", key_id).into());
+            }
+            Ok(plaintext.iter().rev().copied().collect())
+        }
+
+        fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>, KeyringError> {
+            self.encrypt(key_id, ciphertext)
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let encryption = Encryption::new(Arc::new(Reversed)).field("one", "pii");
+
+        let stored = encryption
+            .encrypt("one", Some("secret".to_owned()))
+            .unwrap();
+        assert_eq!(stored.as_deref(), Some("pii:dGVyY2Vz"));
+        assert_eq!(
+            encryption.decrypt("one", stored).unwrap().as_deref(),
+            Some("secret")
+        );
+
+        // other fields and `null` are left alone
+        assert_eq!(
+            encryption.encrypt("two", Some("plain".to_owned())).unwrap(),
+            Some("plain".to_owned())
+        );
+        assert_eq!(encryption.encrypt("one", None).unwrap(), None);
+    }
+
+    #[test]
+    fn unchanged_values_are_not_rewritten() {
+        let encryption = Encryption::new(Arc::new(Reversed)).field("one", "pii");
+        let current = encryption
+            .encrypt("one", Some("secret".to_owned()))
+            .unwrap();
+
+        let mut changed_fields = Vec::new();
+        let stored = encryption
+            .patched(
+                &Some(Some("secret".to_owned())),
+                current.clone(),
+                "one",
+                &mut changed_fields,
+            )
+            .unwrap();
+        assert_eq!(stored, current);
+        assert!(changed_fields.is_empty());
+
+        let stored = encryption
+            .patched(&Some(None), current, "one", &mut changed_fields)
+            .unwrap();
+        assert_eq!(stored, None);
+        assert_eq!(changed_fields, vec!["one"]);
+    }
+}
@@ -0,0 +1,160 @@
+//! Erasing personal data from a user, for example for a GDPR erasure request.
+
+use crate::{
+    diff, patch_log, store, DbPool, Error, InternalId, Options, Outcome, PatchMeta, ProfileUpdate,
+    Update, User,
+};
+
+/// Fields that can be erased, with the table and column they live in.
+pub(crate) const ERASABLE: &[(&str, &str, &str)] = &[
+    ("one", "users", "one"),
+    ("two", "users", "two"),
+    ("metadata", "users", "metadata"),
+    ("profile.bio", "user_profiles", "bio"),
+];
+
+/// Set `fields` to `null` for the user, and remove their old values from `users_history` and
+/// the patches stored in `patches`, all in one transaction.
+///
+/// The erasure itself is stored in `patches` with `meta` so there is a record of who erased what.
+/// Soft deleted users are erased too. Fails with `Error::InvalidConfig` if one of `fields` can't
+/// be erased, and with `Error::WrongTenant` if the user is outside `options.tenant`. Like
+/// `insert_or_update`, the user is invalidated in `options.cache` and the erasure is emitted as a
+/// `tracing` event, with the fields in `options.redact` and `redact::SENSITIVE` redacted.
+pub(crate) async fn erase(
+    pool: &DbPool,
+    internal_id: InternalId,
+    fields: &[&'static str],
+    meta: &PatchMeta,
+    options: &Options,
+) -> Result<Outcome, Error> {
+    let mut columns = Vec::new();
+    for field in fields {
+        let column = ERASABLE
+            .iter()
+            .find(|(name, _, _)| name == field)
+            .ok_or(Error::InvalidConfig(field))?;
+        columns.push(*column);
+    }
+    let users = columns
+        .iter()
+        .filter(|(_, table, _)| *table == "users")
+        .map(|(_, _, column)| *column)
+        .collect::<Vec<_>>();
+    let erase_bio = columns
+        .iter()
+        .any(|(_, table, _)| *table == "user_profiles");
+
+    let mut con = pool.get().await?;
+    let tx = con.transaction().await?;
+
+    let row = tx
+        .query_opt(
+            r#"
+            select users.*, user_profiles.bio
+            from users
+            left join user_profiles using (internal_id)
+            where internal_id = $1
+            for update of users
+            "#,
+            &[&internal_id],
+        )
+        .await?
+        .ok_or(Error::NotFound)?;
+    let before = User::read(&row, options)?;
+    if let Some(tenant) = options.tenant {
+        if before.organization_id != Some(tenant) {
+            return Err(Error::WrongTenant);
+        }
+    }
+
+    // column names come from `ERASABLE` so formatting them into the queries is safe
+    let mut history = users.clone();
+    if erase_bio {
+        history.push("bio");
+        tx.execute(
+            "update user_profiles set bio = null where internal_id = $1",
+            &[&internal_id],
+        )
+        .await?;
+    }
+    if !users.is_empty() {
+        tx.execute(
+            format!(
+                "update users set {}, updated_at = now() where internal_id = $1",
+                set_null(&users)
+            )
+            .as_str(),
+            &[&internal_id],
+        )
+        .await?;
+    }
+    tx.execute(
+        format!(
+            "update users_history set {} where internal_id = $1",
+            set_null(&history)
+        )
+        .as_str(),
+        &[&internal_id],
+    )
+    .await?;
+
+    for field in fields {
+        let path = field.split('.').collect::<Vec<_>>();
+        tx.execute(
+            "update patches set patch = patch #- $2 where internal_id = $1",
+            &[&internal_id, &path],
+        )
+        .await?;
+    }
+
+    let mut erasure = Update::default();
+    for field in fields {
+        erasure.set_null(field);
+    }
+    patch_log::record(&tx, internal_id, &erasure, meta).await?;
+
+    tx.commit().await?;
+
+    let mut after = before.clone();
+    after.apply(&erasure);
+    let changed_fields = store::changed_fields(&before, &after);
+    let outcome = if changed_fields.is_empty() {
+        Outcome::Noop
+    } else {
+        Outcome::Updated { changed_fields }
+    };
+
+    if let Some(cache) = &options.cache {
+        cache.invalidate(internal_id);
+    }
+    diff::emit(
+        internal_id,
+        &outcome,
+        Some(&before),
+        &erasure,
+        &options.redact,
+    );
+
+    Ok(outcome)
+}
+
+fn set_null(columns: &[&str]) -> String {
+    columns
+        .iter()
+        .map(|column| format!("{} = null", column))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Update {
+    fn set_null(&mut self, field: &str) {
+        match field {
+            "one" => self.one = Some(None),
+            "two" => self.two = Some(None),
+            "metadata" => self.metadata = Some(None),
+            "profile.bio" => self.profile = Some(ProfileUpdate { bio: Some(None) }),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::User;
+use sha2::{Digest, Sha256};
+use std::{fmt::Write, time::SystemTime};
+
+impl User {
+    /// A strong ETag for the current state of the user, including the surrounding quotes.
+    pub(crate) fn etag(&self) -> String {
+        let json = serde_json::to_vec(self).expect("users always serialize");
+        let hash = Sha256::digest(&json);
+
+        let mut etag = String::with_capacity(hash.len() * 2 + 2);
+        etag.push('"');
+        for byte in hash {
+            write!(etag, "{:02x}", byte).unwrap();
+        }
+        etag.push('"');
+        etag
+    }
+}
+
+/// Whether an `If-Match` header value matches `etag`.
+///
+/// Uses the strong comparison required for `If-Match` so weak ETags never match.
+pub(crate) fn if_match(header: &str, etag: Option<&str>) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        // `If-Match` never matches a resource that doesn't exist
+        None => return false,
+    };
+
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate == etag)
+}
+
+/// Whether a resource last modified at `updated_at` satisfies `If-Unmodified-Since: since`.
+///
+/// HTTP dates only have second precision so `updated_at` is truncated to whole seconds first,
+/// otherwise a client echoing back `Last-Modified` would always fail.
+pub(crate) fn if_unmodified_since(since: SystemTime, updated_at: SystemTime) -> bool {
+    let secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    };
+    secs(updated_at) <= secs(since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        assert!(if_match(r#""a""#, Some(r#""a""#)));
+        assert!(if_match(r#""b", "a""#, Some(r#""a""#)));
+        assert!(if_match("*", Some(r#""a""#)));
+        assert!(!if_match(r#""b""#, Some(r#""a""#)));
+        assert!(!if_match(r#"W/"a""#, Some(r#""a""#)));
+        assert!(!if_match("*", None));
+    }
+
+    #[test]
+    fn unmodified_since() {
+        use std::time::Duration;
+
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        assert!(if_unmodified_since(since, since));
+        assert!(if_unmodified_since(since, since - Duration::from_secs(1)));
+        assert!(if_unmodified_since(
+            since,
+            since + Duration::from_millis(999)
+        ));
+        assert!(!if_unmodified_since(since, since + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::Error;
+use postgres_types::ToSql;
+use std::sync::{Arc, Mutex};
+use tokio_postgres::{Row, Transaction};
+
+/// Collects the query plans of the statements a patch locks and writes with, for diagnosing slow
+/// patches.
+///
+/// Set `Options::explain` to a clone and read the plans back after the patch is applied.
+/// `insert_or_update` and `insert_or_update_many` clear the plans at the start of every attempt,
+/// so after retries only the last attempt's are left.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Explain(Arc<Mutex<Vec<String>>>);
+
+impl Explain {
+    pub(crate) fn plans(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Execute `sql`, when `explain` is set first capturing its plan with
+/// `explain (analyze, buffers)`.
+///
+/// `analyze` runs the statement so that's done in a savepoint that is rolled back, after which
+/// the statement runs for real. It therefore does its work twice and should only be enabled while
+/// debugging.
+pub(crate) async fn execute(
+    tx: &Transaction<'_>,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+    explain: Option<&Explain>,
+) -> Result<u64, Error> {
+    if let Some(explain) = explain {
+        capture(tx, sql, params, explain).await?;
+    }
+    Ok(tx.execute(sql, params).await?)
+}
+
+/// Like `execute` but for queries, or statements with `returning`, that produce at most one row.
+pub(crate) async fn query_opt(
+    tx: &Transaction<'_>,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+    explain: Option<&Explain>,
+) -> Result<Option<Row>, Error> {
+    if let Some(explain) = explain {
+        capture(tx, sql, params, explain).await?;
+    }
+    Ok(tx.query_opt(sql, params).await?)
+}
+
+async fn capture(
+    tx: &Transaction<'_>,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+    explain: &Explain,
+) -> Result<(), Error> {
+    tx.batch_execute("savepoint explain").await?;
+    let rows = tx
+        .query(
+            format!("explain (analyze, buffers) {}", sql).as_str(),
+            params,
+        )
+        .await?;
+    tx.batch_execute("rollback to savepoint explain; release savepoint explain")
+        .await?;
+
+    let plan = rows
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>()
+        .join("\n");
+    explain.0.lock().unwrap().push(plan);
+    Ok(())
+}
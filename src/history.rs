@@ -0,0 +1,75 @@
+use crate::{tenant_condition, DbPool, Error, InternalId, Options, User};
+use std::time::SystemTime;
+use tokio_postgres::{types::ToSql, Transaction};
+
+/// Close the current version of the user in `users_history` and record the one just written.
+///
+/// Must run after the user has been written, in the same transaction, so the history always
+/// agrees with `users`.
+pub(crate) async fn record(tx: &Transaction<'_>, internal_id: InternalId) -> Result<(), Error> {
+    tx.execute(
+        r#"
+        update users_history
+        set valid_to = now()
+        where internal_id = $1 and valid_to is null
+        "#,
+        &[&internal_id],
+    )
+    .await?;
+
+    tx.execute(
+        r#"
+        insert into users_history (
+            id, internal_id, one, two, metadata, deleted_at, status, locale, organization_id, bio,
+            valid_from
+        )
+        select
+            users.id, internal_id, one, two, metadata, deleted_at, status, locale, organization_id,
+            user_profiles.bio, now()
+        from users
+        left join user_profiles using (internal_id)
+        where internal_id = $1
+        "#,
+        &[&internal_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Fetch the user as it was at `at`, according to `users_history`.
+///
+/// Fails with `Error::NotFound` if no version was valid at that time. Only writes made with
+/// `Options::history` are recorded. Fields in `options.encryption` are decrypted and versions
+/// outside `options.tenant` are left out.
+pub(crate) async fn fetch_as_of(
+    pool: &DbPool,
+    internal_id: InternalId,
+    at: SystemTime,
+    options: &Options,
+) -> Result<User, Error> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&internal_id, &at];
+    let tenant = tenant_condition(options, &mut params);
+
+    let con = pool.get().await?;
+
+    let row = con
+        .query_opt(
+            format!(
+                r#"
+                select *
+                from users_history
+                where internal_id = $1
+                    and valid_from <= $2
+                    and (valid_to is null or valid_to > $2){}
+                "#,
+                tenant,
+            )
+            .as_str(),
+            &params,
+        )
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    User::read(&row, options)
+}
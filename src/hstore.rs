@@ -0,0 +1,51 @@
+use postgres_types::ToSql;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Key level patch of an `hstore` column.
+///
+/// Deserializes from a JSON object. Keys with a string value are set, keys with `null` are
+/// deleted, and keys not in the object are left untouched.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "HashMap<String, Option<String>>")]
+pub(crate) struct HstorePatch {
+    set: HashMap<String, Option<String>>,
+    delete: Vec<String>,
+}
+
+impl From<HashMap<String, Option<String>>> for HstorePatch {
+    fn from(map: HashMap<String, Option<String>>) -> Self {
+        let mut patch = HstorePatch::default();
+        for (key, value) in map {
+            match value {
+                Some(value) => {
+                    patch.set.insert(key, Some(value));
+                }
+                None => patch.delete.push(key),
+            }
+        }
+        patch
+    }
+}
+
+impl HstorePatch {
+    /// Expression for the new value of `column`, for use in `set {column} = ...`.
+    ///
+    /// `column` is formatted into the SQL so it must not come from user input.
+    pub(crate) fn to_sql<'a>(
+        &'a self,
+        column: &str,
+        params: &mut Vec<&'a (dyn ToSql + Sync)>,
+    ) -> String {
+        params.push(&self.set);
+        let set = params.len();
+        params.push(&self.delete);
+        let delete = params.len();
+        format!(
+            "delete(coalesce({column}, ''::hstore) || ${set}::hstore, ${delete}::text[])",
+            column = column,
+            set = set,
+            delete = delete,
+        )
+    }
+}
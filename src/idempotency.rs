@@ -0,0 +1,117 @@
+//! Applying a patch at most once per idempotency key.
+//!
+//! The key is claimed in `idempotency_keys` in the same transaction as the patch, so it only
+//! sticks if the patch commits. A redelivery with the same key waits for the first delivery to
+//! commit and then gets its outcome back without the patch being validated or applied again. A
+//! hash of the patch is stored with the key so reusing it for a different patch is caught.
+
+use crate::{Error, InternalId, Outcome, Update, FIELDS};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use tokio_postgres::Transaction;
+
+/// Claim `key` for `patch`, returning the stored outcome if a patch with this key was already
+/// applied.
+///
+/// Fails with `Error::IdempotencyKeyReused` if that patch was a different one.
+pub(crate) async fn claim(
+    tx: &Transaction<'_>,
+    internal_id: InternalId,
+    key: &str,
+    patch: &Update,
+) -> Result<Option<Outcome>, Error> {
+    let hash = patch_hash(patch);
+
+    // if another transaction holds the key this waits for it to commit or roll back
+    let claimed = tx
+        .execute(
+            r#"
+            insert into idempotency_keys (internal_id, key, patch_hash)
+            values ($1, $2, $3)
+            on conflict (internal_id, key) do nothing
+            "#,
+            &[&internal_id, &key, &hash],
+        )
+        .await?;
+    if claimed == 1 {
+        return Ok(None);
+    }
+
+    let row = tx
+        .query_one(
+            r#"
+            select outcome, id, changed_fields, patch_hash
+            from idempotency_keys
+            where internal_id = $1 and key = $2
+            "#,
+            &[&internal_id, &key],
+        )
+        .await?;
+
+    // keys claimed before hashes were stored have none and aren't checked
+    if row
+        .get::<_, Option<&str>>("patch_hash")
+        .is_some_and(|stored| stored != hash)
+    {
+        return Err(Error::IdempotencyKeyReused);
+    }
+
+    let outcome = match row.get::<_, Option<&str>>("outcome") {
+        Some("inserted") => Outcome::Inserted { id: row.get("id") },
+        Some("updated") => Outcome::Updated {
+            changed_fields: row
+                .get::<_, Vec<&str>>("changed_fields")
+                .into_iter()
+                .filter_map(field_name)
+                .collect(),
+        },
+        _ => Outcome::Noop,
+    };
+    Ok(Some(outcome))
+}
+
+/// Store the outcome of the patch that claimed `key`.
+pub(crate) async fn record(
+    tx: &Transaction<'_>,
+    internal_id: InternalId,
+    key: &str,
+    outcome: &Outcome,
+) -> Result<(), Error> {
+    let (name, id, changed_fields) = match outcome {
+        Outcome::Inserted { id } => ("inserted", Some(*id), None),
+        Outcome::Updated { changed_fields } => ("updated", None, Some(changed_fields)),
+        Outcome::Noop => ("noop", None, None),
+    };
+    tx.execute(
+        r#"
+        update idempotency_keys
+        set outcome = $3, id = $4, changed_fields = $5
+        where internal_id = $1 and key = $2
+        "#,
+        &[&internal_id, &key, &name, &id, &changed_fields],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Hex encoded SHA-256 of the patch as JSON. Missing fields are left out and `null` fields kept,
+/// so patches only hash the same if they mean the same.
+fn patch_hash(patch: &Update) -> String {
+    let json = serde_json::to_vec(patch).expect("patches always serialize");
+    Sha256::digest(&json)
+        .iter()
+        .fold(String::new(), |mut hash, byte| {
+            write!(hash, "{:02x}", byte).unwrap();
+            hash
+        })
+}
+
+/// `Outcome::Updated` holds `&'static str`s so map stored names back to them.
+fn field_name(name: &str) -> Option<&'static str> {
+    // a resurrected user has `deleted_at` changed too
+    FIELDS
+        .iter()
+        .chain(&["deleted_at"])
+        .copied()
+        .find(|field| *field == name)
+}
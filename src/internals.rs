@@ -0,0 +1,20 @@
+//! Parsing entry points for the benches and fuzz targets, which can't name `Update`.
+//!
+//! Not part of the public API. Only built with the `internals` feature.
+
+use crate::{limits, ParseError, PatchLimits, Update};
+
+/// Deserialize a patch with `Update::from_json`, discarding it.
+pub fn from_json(json: &str) -> Result<(), serde_path_to_error::Error<serde_json::Error>> {
+    Update::from_json(json).map(drop)
+}
+
+/// Deserialize a patch with `Update::from_json_with_limits`, discarding it.
+pub fn from_json_with_limits(json: &str, limits: &PatchLimits) -> Result<(), ParseError> {
+    Update::from_json_with_limits(json, limits).map(drop)
+}
+
+/// Deepest nesting of objects and arrays, as checked against `PatchLimits::max_depth`.
+pub fn depth(json: &str) -> usize {
+    limits::depth(json)
+}
@@ -0,0 +1,121 @@
+//! Patches as RFC 6902 JSON Patch operations, for debugging and clients that speak JSON Patch.
+
+use crate::{Update, User, DEFAULT_LOCALE, FIELDS};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// A JSON Patch operation on the user document, as serialized by `User`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub(crate) enum Operation {
+    Add { path: String, value: Value },
+    Replace { path: String, value: Value },
+}
+
+impl Update {
+    /// The patch as JSON Patch operations, in field order.
+    ///
+    /// Without `base` every present field becomes an `add`, which also overwrites an existing
+    /// member. With `base` each field the patch changes becomes a `replace` and fields it sets to
+    /// their current value are left out. `null` is kept as a value since the user document has every
+    /// field, except for `locale` where `null` resets it to its default so that is the value. The
+    /// profile is flattened into the user, so `profile.bio` is `/bio`.
+    pub(crate) fn to_json_patch(&self, base: Option<&User>) -> Vec<Operation> {
+        let base = base.map(|base| match serde_json::to_value(base) {
+            Ok(Value::Object(base)) => base,
+            _ => Map::new(),
+        });
+
+        let mut patch = match serde_json::to_value(self) {
+            Ok(Value::Object(patch)) => patch,
+            _ => unreachable!("patches serialize as objects"),
+        };
+        // profile fields are flattened into the user
+        if let Some(Value::Object(profile)) = patch.remove("profile") {
+            patch.extend(profile);
+        }
+        if let Some(locale @ Value::Null) = patch.get_mut("locale") {
+            *locale = DEFAULT_LOCALE.into();
+        }
+
+        // `Map` is sorted by key so go through the fields in the order they are declared
+        FIELDS
+            .iter()
+            .map(|field| field.strip_prefix("profile.").unwrap_or(*field))
+            .filter_map(|field| {
+                let value = patch.remove(field)?;
+                let path = format!("/{}", field);
+                match &base {
+                    None => Some(Operation::Add { path, value }),
+                    Some(base) if base.get(field) == Some(&value) => None,
+                    Some(_) => Some(Operation::Replace { path, value }),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InternalId;
+    use serde_json::json;
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn without_base() {
+        let patch = update(json!({ "one": "1", "two": null, "profile": { "bio": "hi" } }));
+        assert_eq!(
+            serde_json::to_value(patch.to_json_patch(None)).unwrap(),
+            json!([
+                { "op": "add", "path": "/one", "value": "1" },
+                { "op": "add", "path": "/two", "value": null },
+                { "op": "add", "path": "/bio", "value": "hi" },
+            ])
+        );
+    }
+
+    #[test]
+    fn with_base() {
+        let mut base = User::with_defaults(1, InternalId(1));
+        base.one = Some("1".to_owned());
+
+        let patch = update(json!({ "one": "1", "two": "2", "metadata": null }));
+        assert_eq!(
+            patch.to_json_patch(Some(&base)),
+            vec![Operation::Replace {
+                path: "/two".to_owned(),
+                value: json!("2"),
+            }]
+        );
+    }
+
+    #[test]
+    fn null_locale_is_the_default() {
+        let patch = update(json!({ "locale": null }));
+        assert_eq!(
+            patch.to_json_patch(None),
+            vec![Operation::Add {
+                path: "/locale".to_owned(),
+                value: json!("en"),
+            }]
+        );
+
+        // already the default
+        let base = User::with_defaults(1, InternalId(1));
+        assert!(patch.to_json_patch(Some(&base)).is_empty());
+
+        let mut base = base;
+        base.locale = "da".to_owned();
+        assert_eq!(
+            patch.to_json_patch(Some(&base)),
+            vec![Operation::Replace {
+                path: "/locale".to_owned(),
+                value: json!("en"),
+            }]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use serde::de::{self, value, Deserialize, Deserializer, Visitor};
+use std::{fmt, marker::PhantomData, str::FromStr};
+
+/// A value that may also be sent as a string, such as `"42"` for an integer or `"true"` for a
+/// boolean.
+///
+/// Strings are parsed with `FromStr` and fail with a regular serde error for the field if they
+/// don't parse. Values of the right type are accepted as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lenient<T>(pub T);
+
+impl<'de, T> Deserialize<'de> for Lenient<T>
+where
+    T: Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_any(LenientVisitor(PhantomData))
+            .map(Lenient)
+    }
+}
+
+/// Visitor that parses strings with `FromStr` and forwards everything else to `T`.
+struct LenientVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for LenientVisitor<T>
+where
+    T: Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a value or a string containing one")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        T::deserialize(value::BoolDeserializer::new(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        T::deserialize(value::I64Deserializer::new(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        T::deserialize(value::U64Deserializer::new(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        T::deserialize(value::F64Deserializer::new(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse()
+            .map_err(|err| E::custom(format_args!("invalid value {:?}: {}", v, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(serde::Deserialize)]
+    struct Payload {
+        age: Lenient<i64>,
+        admin: Lenient<bool>,
+    }
+
+    #[test]
+    fn lenient() {
+        let payload =
+            serde_json::from_value::<Payload>(json!({ "age": 42, "admin": true })).unwrap();
+        assert_eq!(payload.age, Lenient(42));
+        assert_eq!(payload.admin, Lenient(true));
+
+        let payload =
+            serde_json::from_value::<Payload>(json!({ "age": "42", "admin": "false" })).unwrap();
+        assert_eq!(payload.age, Lenient(42));
+        assert_eq!(payload.admin, Lenient(false));
+
+        let err = serde_json::from_value::<Payload>(json!({ "age": "old", "admin": true }))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid value \"old\": invalid digit found in string"
+        );
+
+        let err = serde_json::from_value::<Payload>(json!({ "age": 42, "admin": 1 }))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid type: integer `1`, expected a boolean"
+        );
+    }
+}
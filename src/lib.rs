@@ -1,17 +1,133 @@
 #![allow(dead_code)]
 
-use serde::Deserialize;
+mod access;
+mod applier;
+mod batch;
+mod breaker;
+mod cache;
+#[cfg(feature = "cli")]
+pub mod cli;
+mod complete;
+mod diff;
+mod encrypt;
+mod erase;
+mod etag;
+mod explain;
+mod history;
+mod hstore;
+mod idempotency;
+#[cfg(feature = "internals")]
+#[doc(hidden)]
+pub mod internals;
+mod json_patch;
+mod lenient;
+mod limits;
+#[cfg(feature = "load")]
+mod load;
+mod merge;
+mod messages;
+mod normalize;
+mod patch_log;
+mod pool;
+mod presence;
+mod projection;
+mod redact;
+mod retry;
+mod store;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod validate;
+mod versions;
+
+use bb8_postgres::bb8::RunError;
+pub use complete::MissingFields;
+pub use lenient::Lenient;
+pub use limits::{ParseError, PatchLimits};
+pub use merge::{Conflict, Conflicts, StaleField};
+pub use messages::{English, Messages};
+pub use patch_log::PatchMeta;
+pub use pool::{health_check, PoolConfig};
+use postgres_types::{FromSql, ToSql};
+pub use presence::{Maybe, Required};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{borrow::Cow, collections::HashMap, fmt, sync::Arc, time::SystemTime};
+pub use tls::Tls;
+use tokio_postgres::{
+    error::SqlState, types::Json, GenericClient, IsolationLevel, Row, Transaction,
+};
+pub use validate::{Rule, Violation};
 
-type DbPool =
-    bb8_postgres::bb8::Pool<bb8_postgres::PostgresConnectionManager<tokio_postgres::NoTls>>;
+pub type DbPool = bb8_postgres::bb8::Pool<bb8_postgres::PostgresConnectionManager<Tls>>;
 
-#[derive(Deserialize)]
+// `Debug` is in `redact` so sensitive values aren't printed
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
 struct Update {
     // double option to differentiate `null` and "missing"
-    #[serde(default, deserialize_with = "deserialize_some")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
     one: Option<Option<String>>,
-    #[serde(default, deserialize_with = "deserialize_some")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
     two: Option<Option<String>>,
+    // whole document is replaced, there is no merging of nested keys
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    metadata: Option<Option<Value>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    status: Option<Option<UserStatus>>,
+    // `null` resets the locale to the column default
+    #[serde(
+        default,
+        deserialize_with = "normalize::trim",
+        skip_serializing_if = "Option::is_none"
+    )]
+    locale: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    organization_id: Option<Option<i64>>,
+    // lives in `user_profiles` but is written in the same transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<ProfileUpdate>,
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+struct ProfileUpdate {
+    // clients send `""` when the bio is cleared
+    #[serde(
+        default,
+        deserialize_with = "normalize::empty_as_null",
+        skip_serializing_if = "Option::is_none"
+    )]
+    bio: Option<Option<String>>,
+}
+
+/// Bound as the postgres `user_status` enum rather than text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "user_status")]
+enum UserStatus {
+    #[postgres(name = "active")]
+    Active,
+    #[postgres(name = "suspended")]
+    Suspended,
 }
 
 // based on https://github.com/serde-rs/serde/issues/984#issuecomment-314143738
@@ -24,102 +140,1521 @@ where
 }
 
 impl Update {
-    async fn insert_or_update(self, internal_id: i64, pool: &DbPool) {
-        let mut con = pool.get().await.unwrap();
-        let tx = con.transaction().await.unwrap();
+    /// Parse a patch from JSON, reporting where the bad value is on errors.
+    ///
+    /// Errors display as `profile.bio: invalid type: integer `1`, expected a string`.
+    fn from_json(json: &str) -> Result<Self, serde_path_to_error::Error<serde_json::Error>> {
+        let deserializer = &mut serde_json::Deserializer::from_str(json);
+        serde_path_to_error::deserialize(deserializer)
+    }
 
-        // check if row exists, if it does lock it so others cannot query it
-        let row = tx
-            .query_opt(
+    /// The patch as an RFC 7386 `application/merge-patch+json` document, with missing fields left
+    /// out and `null` fields kept.
+    ///
+    /// A merge patch merges nested objects, so a receiver merges `metadata` into its current value
+    /// where `insert_or_update` replaces it.
+    fn to_merge_patch(&self) -> Value {
+        let mut patch = match serde_json::to_value(self).unwrap() {
+            Value::Object(patch) => patch,
+            _ => unreachable!("patches serialize as objects"),
+        };
+        // `{ "profile": {} }` changes nothing so leave it out
+        if patch
+            .get("profile")
+            .and_then(Value::as_object)
+            .is_some_and(Map::is_empty)
+        {
+            patch.remove("profile");
+        }
+        Value::Object(patch)
+    }
+
+    async fn insert_or_update(
+        self,
+        internal_id: InternalId,
+        pool: &DbPool,
+    ) -> Result<Outcome, Error> {
+        self.insert_or_update_with_options(internal_id, pool, &Options::default())
+            .await
+    }
+
+    /// Apply the patch in its own transaction, retrying on serialization failures and deadlocks
+    /// or whatever `Options::retry` says.
+    ///
+    /// Dropping the future, for example when a client disconnects and the request handler is
+    /// cancelled, rolls the transaction back. `tokio-postgres` queues a `rollback` when the
+    /// transaction is dropped and the connection only goes back to the pool behind it, so any
+    /// locks are released as soon as the statement that was running finishes. A statement
+    /// waiting on someone else's lock keeps waiting, set `lock_timeout` through
+    /// `Options::settings` to bound that.
+    async fn insert_or_update_with_options(
+        self,
+        internal_id: InternalId,
+        pool: &DbPool,
+        options: &Options,
+    ) -> Result<Outcome, Error> {
+        retry::retrying(options.retry.as_ref(), || {
+            breaker::call(
+                options.breaker.as_deref(),
+                self.try_insert_or_update(internal_id, pool, options),
+            )
+        })
+        .await
+    }
+
+    async fn try_insert_or_update(
+        &self,
+        internal_id: InternalId,
+        pool: &DbPool,
+        options: &Options,
+    ) -> Result<Outcome, Error> {
+        if let Some(explain) = &options.explain {
+            explain.clear();
+        }
+
+        let mut con = pool.get().await?;
+        let tx = con
+            .build_transaction()
+            .isolation_level(options.isolation_level)
+            .start()
+            .await?;
+        apply_session(&tx, options).await?;
+
+        let mut applied = Applied::default();
+        let outcome = self
+            .apply_in_transaction(internal_id, &tx, options, &mut applied)
+            .await?;
+
+        tx.commit().await?;
+
+        if let Some(cache) = &options.cache {
+            cache.invalidate(internal_id);
+        }
+        diff::emit(
+            internal_id,
+            &outcome,
+            applied.before.as_ref(),
+            applied.patch.as_ref().unwrap_or(self),
+            &options.redact,
+        );
+
+        Ok(outcome)
+    }
+
+    /// Like `insert_or_update` but within a transaction managed by the caller.
+    ///
+    /// Nothing is retried so the caller is responsible for handling serialization failures. The
+    /// isolation level, role, and settings in `options` are ignored since the transaction has
+    /// already been started.
+    async fn insert_or_update_in_transaction(
+        &self,
+        internal_id: InternalId,
+        tx: &Transaction<'_>,
+        options: &Options,
+    ) -> Result<Outcome, Error> {
+        self.apply_in_transaction(internal_id, tx, options, &mut Applied::default())
+            .await
+    }
+
+    /// Does the work of `insert_or_update_in_transaction`, storing the user as it was before the
+    /// patch and the patch that was applied in `applied`.
+    async fn apply_in_transaction(
+        &self,
+        internal_id: InternalId,
+        tx: &Transaction<'_>,
+        options: &Options,
+        applied: &mut Applied,
+    ) -> Result<Outcome, Error> {
+        options.check_fields()?;
+
+        let mut changed_fields = Vec::new();
+
+        // a redelivery gets the recorded outcome back even if the patch wouldn't pass the checks
+        // below anymore, say because a rule or policy changed in between
+        if let Some(key) = &options.idempotency_key {
+            if let Some(outcome) = idempotency::claim(tx, internal_id, key, self).await? {
+                return Ok(outcome);
+            }
+        }
+
+        // everything below, validation included, sees the normalized values
+        let normalized;
+        let this = if options.normalize.is_empty() {
+            self
+        } else {
+            normalized = self.clone().normalized(&options.normalize);
+            &normalized
+        };
+
+        if let Some(policy) = &options.policy {
+            this.check_writable(policy.as_ref())?;
+        }
+        this.validate(&options.rules).map_err(Error::Invalid)?;
+        this.run_validators(internal_id, &options.validators, tx)
+            .await?;
+
+        if options.require_precondition
+            && options.if_match.is_none()
+            && options.if_unmodified_since.is_none()
+        {
+            return Err(Error::PreconditionRequired);
+        }
+
+        if let (Some(tenant), Some(organization_id)) = (options.tenant, &this.organization_id) {
+            if *organization_id != Some(tenant) {
+                return Err(Error::WrongTenant);
+            }
+        }
+
+        // with `ConflictPolicy::Merge` fields that conflict are removed from the patch
+        let mut patch = Cow::Borrowed(this);
+
+        // the generated `id` if the row was inserted
+        let inserted = loop {
+            // check if row exists, if it does lock it so others cannot query it
+            let row = explain::query_opt(
+                tx,
                 r#"
-                select *
+                select users.*, user_profiles.bio
                 from users
+                left join user_profiles using (internal_id)
                 where internal_id = $1
-                for update
+                for update of users
                 "#,
                 &[&internal_id],
+                options.explain.as_ref(),
             )
-            .await
-            .unwrap();
+            .await?;
 
-        if let Some(row) = row {
-            // update the existing row
+            if let Some(row) = row {
+                let encryption = options.encryption.as_ref();
+                // decrypted so ETags are computed from the values clients read
+                let user = &*applied.before.insert(User::read(&row, options)?);
+
+                if let Some(tenant) = options.tenant {
+                    if row.get::<_, Option<i64>>("organization_id") != Some(tenant) {
+                        return Err(Error::WrongTenant);
+                    }
+                }
+
+                let deleted_at: Option<SystemTime> = row.get("deleted_at");
+                if deleted_at.is_some() {
+                    match options.deleted {
+                        DeletedPolicy::Fail => return Err(Error::Deleted),
+                        DeletedPolicy::Skip => return Ok(Outcome::Noop),
+                        DeletedPolicy::Resurrect => changed_fields.push("deleted_at"),
+                    }
+                }
+
+                // patches delivered out of order must not overwrite newer changes
+                if let Some(source_updated_at) = options.source_updated_at {
+                    let current: Option<SystemTime> = row.get("source_updated_at");
+                    if current.is_some_and(|current| current >= source_updated_at) {
+                        return Ok(Outcome::Noop);
+                    }
+                }
+
+                // preconditions are the client's to relax, so they hold whatever `conflict_policy`
+                if let Some(header) = &options.if_match {
+                    let etag = user.etag();
+                    if !etag::if_match(header, Some(&etag)) {
+                        return Err(Error::PreconditionFailed(Some(etag)));
+                    }
+                }
+                if let Some(since) = options.if_unmodified_since {
+                    if !etag::if_unmodified_since(since, row.get("updated_at")) {
+                        return Err(Error::PreconditionFailed(Some(user.etag())));
+                    }
+                }
+
+                if let Some(expected) = &options.expected {
+                    let mut stale = Vec::new();
+                    check_expected(
+                        "one",
+                        &this.one,
+                        &expected.one,
+                        user.one.clone(),
+                        &mut stale,
+                    );
+                    check_expected(
+                        "two",
+                        &this.two,
+                        &expected.two,
+                        user.two.clone(),
+                        &mut stale,
+                    );
+                    check_expected(
+                        "metadata",
+                        &this.metadata,
+                        &expected.metadata,
+                        row.get("metadata"),
+                        &mut stale,
+                    );
+                    check_expected(
+                        "status",
+                        &this.status,
+                        &expected.status,
+                        row.get("status"),
+                        &mut stale,
+                    );
+                    check_expected(
+                        "locale",
+                        &this.locale,
+                        &expected.locale,
+                        row.get("locale"),
+                        &mut stale,
+                    );
+                    check_expected(
+                        "organization_id",
+                        &this.organization_id,
+                        &expected.organization_id,
+                        row.get("organization_id"),
+                        &mut stale,
+                    );
+                    if let (Some(patch), Some(expected)) = (&this.profile, &expected.profile) {
+                        check_expected(
+                            "profile.bio",
+                            &patch.bio,
+                            &expected.bio,
+                            user.bio.clone(),
+                            &mut stale,
+                        );
+                    }
+
+                    if !stale.is_empty() {
+                        match options.conflict_policy {
+                            ConflictPolicy::Reject => return Err(Error::Stale(stale)),
+                            ConflictPolicy::LastWriteWins => {}
+                            ConflictPolicy::Merge => {
+                                let patch = patch.to_mut();
+                                for stale in &stale {
+                                    patch.remove_field(stale.field);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // if value wasn't specified set it to the current value
+                let one = encrypt::patched_text(
+                    encryption,
+                    &patch.one,
+                    row.get("one"),
+                    "one",
+                    &mut changed_fields,
+                )?;
+                let two = encrypt::patched_text(
+                    encryption,
+                    &patch.two,
+                    row.get("two"),
+                    "two",
+                    &mut changed_fields,
+                )?;
+                let metadata = patched(
+                    &patch.metadata,
+                    row.get("metadata"),
+                    "metadata",
+                    &mut changed_fields,
+                );
+                let status = patched(
+                    &patch.status,
+                    row.get("status"),
+                    "status",
+                    &mut changed_fields,
+                );
+                let locale = patched(
+                    &patch.locale,
+                    row.get("locale"),
+                    "locale",
+                    &mut changed_fields,
+                );
+                let organization_id = patched(
+                    &patch.organization_id,
+                    row.get("organization_id"),
+                    "organization_id",
+                    &mut changed_fields,
+                );
+
+                // update the existing row, unless the patch doesn't change anything. A newer
+                // `source_updated_at` is always recorded so older patches are still rejected
+                if !changed_fields.is_empty() || options.source_updated_at.is_some() {
+                    let metadata = metadata.map(Json);
+                    let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+                        &internal_id,
+                        &one,
+                        &two,
+                        &metadata,
+                        &status,
+                        &organization_id,
+                        &options.source_updated_at,
+                    ];
+                    let locale = value_or_default(&locale, &mut params);
+
+                    explain::execute(
+                        tx,
+                        format!(
+                            r#"
+                            update users
+                            set
+                                one = $2
+                                , two = $3
+                                , metadata = $4
+                                , status = $5
+                                , organization_id = $6
+                                , source_updated_at = coalesce($7, source_updated_at)
+                                , locale = {locale}
+                                , deleted_at = null
+                                , updated_at = now()
+                            where internal_id = $1
+                            "#,
+                            locale = locale,
+                        )
+                        .as_str(),
+                        &params,
+                        options.explain.as_ref(),
+                    )
+                    .await?;
+                }
+                break None;
+            }
+
+            if let Some(header) = &options.if_match {
+                if !etag::if_match(header, None) {
+                    return Err(Error::PreconditionFailed(None));
+                }
+            }
+
+            // unspecified values get the insert default, which is null unless stated otherwise
+            let encryption = options.encryption.as_ref();
+            let one = encrypt::encrypt(encryption, "one", inserted(&patch.one, None))?;
+            let two = encrypt::encrypt(encryption, "two", inserted(&patch.two, None))?;
+            let metadata = inserted(&patch.metadata, None).map(Json);
+            let status = inserted(&patch.status, Some(UserStatus::Active));
+            let locale = inserted(&patch.locale, None);
+            let organization_id = options
+                .tenant
+                .or_else(|| inserted(&patch.organization_id, None));
+
+            let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+                &internal_id,
+                &one,
+                &two,
+                &metadata,
+                &status,
+                &organization_id,
+                &options.source_updated_at,
+            ];
+            let locale = value_or_default(&locale, &mut params);
+
+            // another transaction might have inserted the row since we checked, in which case
+            // `on conflict` waits for it to commit and we insert nothing
+            let row = explain::query_opt(
+                tx,
+                format!(
+                    r#"
+                    insert into users (
+                        internal_id, one, two, metadata, status, organization_id,
+                        source_updated_at, locale
+                    )
+                    values ($1, $2, $3, $4, $5, $6, $7, {locale})
+                    on conflict (internal_id) do nothing
+                    returning id
+                    "#,
+                    locale = locale,
+                )
+                .as_str(),
+                &params,
+                options.explain.as_ref(),
+            )
+            .await?;
+
+            if let Some(row) = row {
+                break Some(row.get("id"));
+            }
+
+            // we lost the race so go back and update the row the other transaction inserted. With
+            // repeatable read or serializable isolation the row isn't visible to our snapshot so
+            // postgres instead fails with a serialization error and we retry the transaction
+        };
+
+        if let Some(profile) = &patch.profile {
+            profile
+                .insert_or_update_in_transaction(
+                    internal_id,
+                    tx,
+                    options.encryption.as_ref(),
+                    &mut changed_fields,
+                )
+                .await?;
+        }
+
+        if options.history && (inserted.is_some() || !changed_fields.is_empty()) {
+            history::record(tx, internal_id).await?;
+        }
+
+        if let Some(meta) = &options.log {
+            match &options.encryption {
+                Some(encryption) => {
+                    patch_log::record(tx, internal_id, &patch.encrypted(encryption)?, meta).await?
+                }
+                None => patch_log::record(tx, internal_id, &patch, meta).await?,
+            }
+        }
+
+        let outcome = if let Some(id) = inserted {
+            Outcome::Inserted { id }
+        } else if changed_fields.is_empty() {
+            Outcome::Noop
+        } else {
+            Outcome::Updated { changed_fields }
+        };
+
+        if let Some(key) = &options.idempotency_key {
+            idempotency::record(tx, internal_id, key, &outcome).await?;
+        }
+
+        applied.patch = Some(patch.into_owned());
+        Ok(outcome)
+    }
+
+    /// Remove a field from the patch so it is left untouched.
+    fn remove_field(&mut self, field: &str) {
+        match field {
+            "one" => self.one = None,
+            "two" => self.two = None,
+            "metadata" => self.metadata = None,
+            "status" => self.status = None,
+            "locale" => self.locale = None,
+            "organization_id" => self.organization_id = None,
+            "profile.bio" => {
+                if let Some(profile) = &mut self.profile {
+                    profile.bio = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply the patch to every user matching `filter`, returning how many users were updated.
+    ///
+    /// Missing fields are left untouched on every row, and `updated_at` is bumped on every
+    /// matching row, also when only `profile.bio` is set. Soft deleted users are excluded.
+    ///
+    /// The patch is normalized and checked against `options.policy` and `options.rules` like by
+    /// `insert_or_update`, and `options.validators` run once per matching user. The users are
+    /// invalidated in `options.cache` after committing. `options.tenant` and `options.encryption`
+    /// apply too, everything else in `options` is ignored.
+    async fn update_where(
+        &self,
+        filter: &UserFilter,
+        pool: &DbPool,
+        options: &Options,
+    ) -> Result<u64, Error> {
+        options.check_fields()?;
+
+        let normalized;
+        let this = if options.normalize.is_empty() {
+            self
+        } else {
+            normalized = self.clone().normalized(&options.normalize);
+            &normalized
+        };
+
+        if let Some(policy) = &options.policy {
+            this.check_writable(policy.as_ref())?;
+        }
+        this.validate(&options.rules).map_err(Error::Invalid)?;
+
+        if let (Some(tenant), Some(organization_id)) = (options.tenant, &this.organization_id) {
+            if *organization_id != Some(tenant) {
+                return Err(Error::WrongTenant);
+            }
+        }
+
+        let encryption = options.encryption.as_ref();
+        let one = this
+            .one
+            .clone()
+            .map(|one| encrypt::encrypt(encryption, "one", one))
+            .transpose()?;
+        let two = this
+            .two
+            .clone()
+            .map(|two| encrypt::encrypt(encryption, "two", two))
+            .transpose()?;
+        let bio = this
+            .profile
+            .as_ref()
+            .and_then(|profile| profile.bio.clone())
+            .map(|bio| encrypt::encrypt(encryption, "profile.bio", bio))
+            .transpose()?;
+
+        let mut con = pool.get().await?;
+        let tx = con.transaction().await?;
+
+        // lock the matching users so validators see the rows that are updated
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let where_clause = filter.to_sql(&mut params);
+        let tenant = tenant_condition(options, &mut params);
+        let internal_ids = tx
+            .query(
+                format!(
+                    r#"
+                    select internal_id
+                    from users
+                    where deleted_at is null and {}{}
+                    order by internal_id
+                    for update
+                    "#,
+                    where_clause, tenant,
+                )
+                .as_str(),
+                &params,
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("internal_id"))
+            .collect::<Vec<InternalId>>();
+
+        for internal_id in &internal_ids {
+            this.run_validators(*internal_id, &options.validators, &tx)
+                .await?;
+        }
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&internal_ids];
+        let mut set = Vec::new();
+        if let Some(one) = &one {
+            params.push(one);
+            set.push(format!("one = ${}", params.len()));
+        }
+        if let Some(two) = &two {
+            params.push(two);
+            set.push(format!("two = ${}", params.len()));
+        }
+        if let Some(metadata) = &this.metadata {
+            params.push(metadata);
+            set.push(format!("metadata = ${}", params.len()));
+        }
+        if let Some(status) = &this.status {
+            params.push(status);
+            set.push(format!("status = ${}", params.len()));
+        }
+        if let Some(locale) = &this.locale {
+            set.push(format!(
+                "locale = {}",
+                value_or_default(locale, &mut params)
+            ));
+        }
+        if let Some(organization_id) = &this.organization_id {
+            params.push(organization_id);
+            set.push(format!("organization_id = ${}", params.len()));
+        }
+
+        if set.is_empty() && bio.is_none() {
+            return Ok(0);
+        }
+        set.push("updated_at = now()".to_owned());
+
+        let updated = tx
+            .execute(
+                format!(
+                    "update users set {} where internal_id = any($1)",
+                    set.join(", "),
+                )
+                .as_str(),
+                &params,
+            )
+            .await?;
+
+        // profiles might not exist yet so upsert them for every matching user
+        if let Some(bio) = &bio {
             tx.execute(
                 r#"
-                update users
-                set
-                    one = $2
-                    , two = $3
+                insert into user_profiles (internal_id, bio)
+                select internal_id, $2
+                from users
+                where internal_id = any($1)
+                on conflict (internal_id) do update set bio = excluded.bio
+                "#,
+                &[&internal_ids, bio],
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        if let Some(cache) = &options.cache {
+            for internal_id in &internal_ids {
+                cache.invalidate(*internal_id);
+            }
+        }
+
+        Ok(updated)
+    }
+}
+
+/// Which users `Update::update_where` and `list` apply to.
+///
+/// Columns are fixed by the variants and values are always bound as parameters, so filters are
+/// safe to build from user input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum UserFilter {
+    OrganizationId(i64),
+    Status(UserStatus),
+    Locale(String),
+    /// Users without an organization.
+    NoOrganization,
+    And(Vec<UserFilter>),
+    Or(Vec<UserFilter>),
+}
+
+impl UserFilter {
+    fn and(self, other: UserFilter) -> UserFilter {
+        match self {
+            UserFilter::And(mut filters) => {
+                filters.push(other);
+                UserFilter::And(filters)
+            }
+            filter => UserFilter::And(vec![filter, other]),
+        }
+    }
+
+    fn or(self, other: UserFilter) -> UserFilter {
+        match self {
+            UserFilter::Or(mut filters) => {
+                filters.push(other);
+                UserFilter::Or(filters)
+            }
+            filter => UserFilter::Or(vec![filter, other]),
+        }
+    }
+
+    fn to_sql<'a>(&'a self, params: &mut Vec<&'a (dyn ToSql + Sync)>) -> String {
+        match self {
+            UserFilter::OrganizationId(organization_id) => {
+                params.push(organization_id);
+                format!("organization_id = ${}", params.len())
+            }
+            UserFilter::Status(status) => {
+                params.push(status);
+                format!("status = ${}", params.len())
+            }
+            UserFilter::Locale(locale) => {
+                params.push(locale);
+                format!("locale = ${}", params.len())
+            }
+            UserFilter::NoOrganization => "organization_id is null".to_owned(),
+            // empty `and` matches everything and empty `or` matches nothing, same as `all` and
+            // `any` on an empty iterator
+            UserFilter::And(filters) => join_filters(filters, " and ", "true", params),
+            UserFilter::Or(filters) => join_filters(filters, " or ", "false", params),
+        }
+    }
+}
+
+fn join_filters<'a>(
+    filters: &'a [UserFilter],
+    separator: &str,
+    empty: &str,
+    params: &mut Vec<&'a (dyn ToSql + Sync)>,
+) -> String {
+    if filters.is_empty() {
+        return empty.to_owned();
+    }
+    let conditions = filters
+        .iter()
+        .map(|filter| filter.to_sql(params))
+        .collect::<Vec<_>>();
+    format!("({})", conditions.join(separator))
+}
+
+/// Record `field` in `stale` if the patch writes it but its current value isn't the one the client
+/// expected.
+fn check_expected<T>(
+    field: &'static str,
+    patch: &Option<Option<T>>,
+    expected: &Option<Option<T>>,
+    current: Option<T>,
+    stale: &mut Vec<StaleField>,
+) where
+    T: PartialEq + Serialize,
+{
+    if let (Some(_), Some(expected)) = (patch, expected) {
+        if *expected != current {
+            stale.push(StaleField {
+                field,
+                expected: serde_json::to_value(expected).unwrap_or(Value::Null),
+                current: serde_json::to_value(&current).unwrap_or(Value::Null),
+            });
+        }
+    }
+}
+
+/// The value a column should be inserted with, `default` is used if the field wasn't specified.
+fn inserted<T>(patch: &Option<Option<T>>, default: Option<T>) -> Option<T>
+where
+    T: Clone,
+{
+    match patch {
+        Some(value) => value.clone(),
+        None => default,
+    }
+}
+
+/// SQL for setting a column to `value`, or to its default if `value` is null.
+///
+/// Used for columns where `null` means "reset to the default" rather than literally null.
+fn value_or_default<'a, T>(value: &'a Option<T>, params: &mut Vec<&'a (dyn ToSql + Sync)>) -> String
+where
+    T: ToSql + Sync,
+{
+    match value {
+        Some(value) => {
+            params.push(value);
+            format!("${}", params.len())
+        }
+        None => "default".to_owned(),
+    }
+}
+
+/// The value a column should be updated to, recording `field` in `changed_fields` if it differs
+/// from the current value.
+fn patched<T>(
+    patch: &Option<Option<T>>,
+    current: Option<T>,
+    field: &'static str,
+    changed_fields: &mut Vec<&'static str>,
+) -> Option<T>
+where
+    T: Clone + PartialEq,
+{
+    match patch {
+        Some(value) => {
+            if *value != current {
+                changed_fields.push(field);
+            }
+            value.clone()
+        }
+        None => current,
+    }
+}
+
+impl ProfileUpdate {
+    /// Must be called after the `users` row has been locked or inserted.
+    async fn insert_or_update_in_transaction(
+        &self,
+        internal_id: InternalId,
+        tx: &Transaction<'_>,
+        encryption: Option<&encrypt::Encryption>,
+        changed_fields: &mut Vec<&'static str>,
+    ) -> Result<(), Error> {
+        // we hold the lock on the `users` row so nobody else can insert the profile concurrently
+        let row = tx
+            .query_opt(
+                r#"
+                select *
+                from user_profiles
                 where internal_id = $1
+                for update
                 "#,
-                &[
-                    &internal_id,
-                    // if value wasn't specified set it to the current value
-                    &self.one.unwrap_or_else(|| row.get("one")),
-                    &self.two.unwrap_or_else(|| row.get("two")),
-                ],
+                &[&internal_id],
             )
-            .await
-            .unwrap();
+            .await?;
+
+        if let Some(row) = row {
+            let changed_before = changed_fields.len();
+            let bio = encrypt::patched_text(
+                encryption,
+                &self.bio,
+                row.get("bio"),
+                "profile.bio",
+                changed_fields,
+            )?;
+
+            if changed_fields.len() > changed_before {
+                tx.execute(
+                    r#"
+                    update user_profiles
+                    set bio = $2
+                    where internal_id = $1
+                    "#,
+                    &[&internal_id, &bio],
+                )
+                .await?;
+            }
         } else {
+            let bio = self.bio.clone().flatten();
+            if bio.is_some() {
+                changed_fields.push("profile.bio");
+            }
+            let bio = encrypt::encrypt(encryption, "profile.bio", bio)?;
+
             tx.execute(
                 r#"
-                insert into users (internal_id, one, two)
-                values ($1, $2, $3)
+                insert into user_profiles (internal_id, bio)
+                values ($1, $2)
                 "#,
-                &[
-                    &internal_id,
-                    // null and unspecified is the same for initial insert
-                    &self.one.flatten(),
-                    &self.two.flatten(),
-                ]
+                &[&internal_id, &bio],
             )
-            .await
-            .unwrap();
-        };
+            .await?;
+        }
 
-        tx.commit().await.unwrap();
+        Ok(())
+    }
+}
+
+/// What `apply_in_transaction` read and wrote, for the `tracing` event emitted after commit.
+#[derive(Debug, Default)]
+struct Applied {
+    /// The user as it was before the patch, if it existed.
+    before: Option<User>,
+    /// The patch as it was applied, normalized and without the fields `ConflictPolicy::Merge`
+    /// removed.
+    patch: Option<Update>,
+}
+
+/// What `insert_or_update` ended up doing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    /// The row didn't exist and was inserted with this `id`.
+    Inserted { id: i64 },
+    /// The row existed and the patch changed these fields.
+    Updated { changed_fields: Vec<&'static str> },
+    /// The row existed and the patch didn't change anything.
+    Noop,
+}
+
+struct Options {
+    isolation_level: IsolationLevel,
+    deleted: DeletedPolicy,
+    /// The values the client last saw for the fields it is writing.
+    ///
+    /// If any field in the patch is also present here but the row currently holds a different
+    /// value the patch fails with `Error::Stale`. Fields not present here aren't checked.
+    expected: Option<Update>,
+    /// Value of an `If-Match` header to check against the current row's `User::etag`.
+    ///
+    /// The patch fails with `Error::PreconditionFailed` if it doesn't match.
+    if_match: Option<String>,
+    /// Value of an `If-Unmodified-Since` header. The patch fails with `Error::PreconditionFailed`
+    /// if the user has been updated since.
+    if_unmodified_since: Option<SystemTime>,
+    /// Fail with `Error::PreconditionRequired` unless `if_match` or `if_unmodified_since` is set.
+    require_precondition: bool,
+    /// What to do when `expected` detects a conflict. `if_match` and `if_unmodified_since` are
+    /// checked whatever the policy.
+    conflict_policy: ConflictPolicy,
+    /// Record every version of the user in `users_history`, see `history::fetch_as_of`.
+    history: bool,
+    /// Role to switch to for the duration of the transaction, like `set local role`.
+    ///
+    /// Row level security policies apply to this role rather than the one the pool connects as.
+    role: Option<String>,
+    /// Settings applied for the duration of the transaction, like `set local`, such as
+    /// `("app.tenant_id", "1")` for use in row level security policies.
+    settings: Vec<(String, String)>,
+    /// Organization the patch is scoped to.
+    ///
+    /// Inserted users belong to it, and patching a user in another organization, or moving a user
+    /// to another organization, fails with `Error::WrongTenant`. Reads given the options, and
+    /// `update_where`, leave out users in other organizations.
+    tenant: Option<i64>,
+    /// Store the patch as applied, along with who made it, in `patches`. That is after
+    /// `normalize` and without the fields `ConflictPolicy::Merge` removed.
+    log: Option<PatchMeta>,
+    /// When the source of the patch made the change, such as the timestamp of a webhook event.
+    ///
+    /// Patches older than the last one applied are skipped with `Outcome::Noop`, so events
+    /// delivered out of order don't overwrite newer data.
+    source_updated_at: Option<SystemTime>,
+    /// Cache to invalidate the user in after committing.
+    cache: Option<Arc<dyn cache::UserCache>>,
+    /// Fields whose values are left out of the `tracing` event emitted for every applied patch, in
+    /// addition to `redact::SENSITIVE`.
+    redact: Vec<&'static str>,
+    /// Rules the patch must satisfy, otherwise it fails with `Error::Invalid`. Rules naming a field
+    /// patches don't have fail with `Error::InvalidConfig`.
+    rules: Vec<Rule>,
+    /// Per-field checks run inside the transaction before anything is written. Validators of a
+    /// field patches don't have fail with `Error::InvalidConfig`.
+    validators: Vec<Arc<dyn validate::Validator>>,
+    /// Normalization applied to present text fields before validation, keyed by field name. Other
+    /// fields fail with `Error::InvalidConfig`.
+    normalize: Vec<(&'static str, normalize::Pipeline)>,
+    /// Capture the plans of the `users` lookup, insert, and update, see `explain::Explain`.
+    explain: Option<explain::Explain>,
+    /// Apply the patch at most once per key and user. Replaying a key returns the outcome of the
+    /// patch that first used it without applying anything, reusing it for a different patch fails
+    /// with `Error::IdempotencyKeyReused`.
+    ///
+    /// Only for single patches, batches and the applier fail with `Error::InvalidConfig` since
+    /// every patch in them would share the key.
+    idempotency_key: Option<String>,
+    /// Fields encrypted before they are written, see `encrypt::Encryption`. Add them to `redact`
+    /// too so their values stay out of the logs.
+    encryption: Option<encrypt::Encryption>,
+    /// Fields the caller may write. Patches with other fields fail with `Error::Forbidden`. Fetch
+    /// with `access::fetch_masked` and the same policy to limit what they can read.
+    policy: Option<Arc<dyn access::FieldPolicy>>,
+    /// Which failed transactions are retried, see `retry::RetryPolicy`.
+    retry: Arc<dyn retry::RetryPolicy>,
+    /// Fail fast with `Error::Unavailable` while the database is unreachable. Share one breaker
+    /// between all options using the same pool.
+    breaker: Option<Arc<breaker::CircuitBreaker>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            isolation_level: IsolationLevel::ReadCommitted,
+            deleted: DeletedPolicy::Fail,
+            expected: None,
+            if_match: None,
+            if_unmodified_since: None,
+            require_precondition: false,
+            conflict_policy: ConflictPolicy::Reject,
+            history: false,
+            role: None,
+            settings: Vec::new(),
+            tenant: None,
+            log: None,
+            source_updated_at: None,
+            cache: None,
+            redact: Vec::new(),
+            rules: Vec::new(),
+            validators: Vec::new(),
+            normalize: Vec::new(),
+            explain: None,
+            idempotency_key: None,
+            encryption: None,
+            policy: None,
+            retry: Arc::new(retry::Backoff::default()),
+            breaker: None,
+        }
+    }
+}
+
+/// Apply the role and settings from `options` to the transaction.
+async fn apply_session(tx: &Transaction<'_>, options: &Options) -> Result<(), Error> {
+    // `set local` doesn't accept parameters but `set_config` does, and setting `role` with it is
+    // the same as `set local role`
+    if let Some(role) = &options.role {
+        tx.execute("select set_config('role', $1, true)", &[role])
+            .await?;
+    }
+    for (name, value) in &options.settings {
+        tx.execute("select set_config($1, $2, true)", &[name, value])
+            .await?;
     }
+    Ok(())
 }
 
+/// What to do when a patch conflicts with concurrent changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictPolicy {
+    /// Fail with `Error::Stale`.
+    Reject,
+    /// Ignore the conflict and apply the whole patch.
+    LastWriteWins,
+    /// Apply the fields that don't conflict and keep the current value of those that do.
+    Merge,
+}
+
+/// What to do when patching a row that has been soft deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeletedPolicy {
+    /// Return `Error::Deleted`.
+    Fail,
+    /// Apply the patch and clear `deleted_at`.
+    Resurrect,
+    /// Leave the row untouched.
+    Skip,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Postgres(tokio_postgres::Error),
+    Pool(RunError<tokio_postgres::Error>),
+    /// The row has been soft deleted.
+    Deleted,
+    /// The row doesn't exist.
+    NotFound,
+    /// A configuration value, such as an environment variable, couldn't be parsed.
+    InvalidConfig(&'static str),
+    /// The operation didn't finish within its deadline.
+    Timeout,
+    /// Fields the patch writes no longer hold the values the client expected.
+    Stale(Vec<StaleField>),
+    /// `If-Match` didn't match. Contains the current ETag, if the row exists.
+    PreconditionFailed(Option<String>),
+    /// Neither `If-Match` nor `If-Unmodified-Since` was given but `Options::require_precondition`
+    /// is set.
+    PreconditionRequired,
+    /// The row belongs to another organization than `Options::tenant`.
+    WrongTenant,
+    /// A stored patch couldn't be read back.
+    InvalidPatch,
+    /// A patch couldn't be parsed.
+    Parse(ParseError),
+    /// The patch broke some of `Options::rules`.
+    Invalid(Vec<Violation>),
+    /// A field couldn't be encrypted or decrypted.
+    Encryption(encrypt::KeyringError),
+    /// The patch writes fields that `Options::policy` doesn't allow.
+    Forbidden(Vec<&'static str>),
+    /// `Options::breaker` is open since the database has been unreachable.
+    Unavailable,
+    /// `Options::idempotency_key` was already used for a different patch to the same user.
+    IdempotencyKeyReused,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Postgres(err) => write!(f, "database error: {}", err),
+            Error::Pool(err) => write!(f, "failed to get connection from pool: {}", err),
+            Error::Deleted => write!(f, "row has been deleted"),
+            Error::NotFound => write!(f, "row not found"),
+            Error::InvalidConfig(name) => write!(f, "invalid configuration value for `{}`", name),
+            Error::Timeout => write!(f, "operation timed out"),
+            Error::Stale(fields) => {
+                let fields = fields.iter().map(|field| field.field).collect::<Vec<_>>();
+                write!(f, "fields changed concurrently: {}", fields.join(", "))
+            }
+            Error::PreconditionFailed(_) => write!(f, "precondition failed"),
+            Error::PreconditionRequired => write!(f, "precondition required"),
+            Error::WrongTenant => write!(f, "row belongs to another organization"),
+            Error::InvalidPatch => write!(f, "stored patch is invalid"),
+            Error::Parse(err) => write!(f, "couldn't parse patch: {}", err),
+            Error::Invalid(violations) => {
+                let violations = violations
+                    .iter()
+                    .map(|violation| violation.to_string())
+                    .collect::<Vec<_>>();
+                write!(f, "invalid patch: {}", violations.join(", "))
+            }
+            Error::Encryption(err) => write!(f, "encryption failed: {}", err),
+            Error::Forbidden(fields) => write!(f, "not allowed to write {}", fields.join(", ")),
+            Error::Unavailable => write!(f, "database is unavailable"),
+            Error::IdempotencyKeyReused => {
+                write!(f, "idempotency key was already used for a different patch")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Postgres(err) => Some(err),
+            Error::Pool(err) => Some(err),
+            Error::Encryption(err) => Some(err.as_ref()),
+            Error::Parse(err) => Some(err),
+            Error::Deleted
+            | Error::NotFound
+            | Error::InvalidConfig(_)
+            | Error::Timeout
+            | Error::Stale(_)
+            | Error::PreconditionFailed(_)
+            | Error::PreconditionRequired
+            | Error::WrongTenant
+            | Error::InvalidPatch
+            | Error::Invalid(_)
+            | Error::Forbidden(_)
+            | Error::Unavailable
+            | Error::IdempotencyKeyReused => None,
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for Error {
+    fn from(err: tokio_postgres::Error) -> Self {
+        Error::Postgres(err)
+    }
+}
+
+impl From<RunError<tokio_postgres::Error>> for Error {
+    fn from(err: RunError<tokio_postgres::Error>) -> Self {
+        Error::Pool(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+/// How many times a transaction is attempted before giving up on retryable errors.
+const MAX_ATTEMPTS: usize = 10;
+
+/// Whether the whole transaction can be safely retried.
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::Postgres(err) => matches!(
+            err.code(),
+            Some(&SqlState::T_R_SERIALIZATION_FAILURE) | Some(&SqlState::T_R_DEADLOCK_DETECTED)
+        ),
+        Error::Pool(_)
+        | Error::Deleted
+        | Error::NotFound
+        | Error::InvalidConfig(_)
+        | Error::Timeout
+        | Error::Stale(_)
+        | Error::PreconditionFailed(_)
+        | Error::PreconditionRequired
+        | Error::WrongTenant
+        | Error::InvalidPatch
+        | Error::Parse(_)
+        | Error::Invalid(_)
+        | Error::Encryption(_)
+        | Error::Forbidden(_)
+        | Error::Unavailable
+        | Error::IdempotencyKeyReused => false,
+    }
+}
+
+/// The key patches are applied by.
+///
+/// A newtype since `users` has two `bigint` keys, and passing the serial `id` where
+/// `internal_id` belongs would silently patch the wrong user.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, ToSql, FromSql,
+)]
+#[serde(transparent)]
+#[postgres(transparent)]
+struct InternalId(i64);
+
+impl fmt::Display for InternalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Default of `users.locale`, which inserts without a locale and `"locale": null` get.
+const DEFAULT_LOCALE: &str = "en";
+
+/// Fields that can be written by a patch, named as in patches so the profile's bio is
+/// `profile.bio`. The other lists of fields are checked against this one in the tests.
+const FIELDS: &[&str] = &[
+    "one",
+    "two",
+    "metadata",
+    "status",
+    "locale",
+    "organization_id",
+    "profile.bio",
+];
+
+// `Debug` is in `redact` so sensitive values aren't printed
+#[derive(Clone, Serialize)]
 struct User {
     id: i64,
-    internal_id: i64,
+    internal_id: InternalId,
     one: Option<String>,
     two: Option<String>,
+    metadata: Option<Value>,
+    deleted_at: Option<SystemTime>,
+    status: Option<UserStatus>,
+    locale: String,
+    organization_id: Option<i64>,
+    bio: Option<String>,
+}
+
+/// A patch that writes every field, so a full replacement (`PUT`) can go through the same code as
+/// a partial update. Fields that are `None` are written as `null`.
+impl From<User> for Update {
+    fn from(user: User) -> Self {
+        Update {
+            one: Some(user.one),
+            two: Some(user.two),
+            metadata: Some(user.metadata),
+            status: Some(user.status),
+            locale: Some(Some(user.locale)),
+            organization_id: Some(user.organization_id),
+            profile: Some(ProfileUpdate {
+                bio: Some(user.bio),
+            }),
+        }
+    }
 }
 
-async fn fetch(pool: &DbPool, internal_id: i64) -> User {
-    let con = pool.get().await.unwrap();
+/// Fetch a user, soft deleted users are excluded.
+async fn fetch(pool: &DbPool, internal_id: InternalId) -> Result<User, Error> {
+    fetch_with_options(pool, internal_id, &Options::default()).await
+}
 
-    let row = con
-        .query_one(
-            "select * from users where internal_id = $1",
-            &[&internal_id],
+/// Like `fetch` but returns `None` if the user doesn't exist.
+async fn fetch_opt(pool: &DbPool, internal_id: InternalId) -> Result<Option<User>, Error> {
+    fetch_opt_with_options(pool, internal_id, &Options::default()).await
+}
+
+/// Like `fetch` but reads the user the way `options` writes it, so the fields in
+/// `Options::encryption` are decrypted.
+async fn fetch_with_options(
+    pool: &DbPool,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<User, Error> {
+    fetch_opt_with_options(pool, internal_id, options)
+        .await?
+        .ok_or(Error::NotFound)
+}
+
+/// Like `fetch_with_options` but returns `None` if the user doesn't exist.
+async fn fetch_opt_with_options(
+    pool: &DbPool,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<Option<User>, Error> {
+    let con = pool.get().await?;
+    query_user(&*con, FETCH_QUERY, internal_id, options).await
+}
+
+/// Fetch all users with the given keys, ordered by key. Keys without a user are ignored.
+async fn fetch_many(
+    pool: &DbPool,
+    internal_ids: &[InternalId],
+    options: &Options,
+) -> Result<Vec<User>, Error> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&internal_ids];
+    let tenant = tenant_condition(options, &mut params);
+
+    let con = pool.get().await?;
+
+    let rows = con
+        .query(
+            format!(
+                r#"
+                select users.*, user_profiles.bio
+                from users
+                left join user_profiles using (internal_id)
+                where internal_id = any($1) and deleted_at is null{}
+                order by internal_id
+                "#,
+                tenant,
+            )
+            .as_str(),
+            &params,
         )
-        .await
-        .unwrap();
+        .await?;
+
+    rows.iter().map(|row| User::read(row, options)).collect()
+}
+
+/// Like `fetch_many` but keyed by `internal_id`.
+async fn fetch_many_by_key(
+    pool: &DbPool,
+    internal_ids: &[InternalId],
+    options: &Options,
+) -> Result<HashMap<InternalId, User>, Error> {
+    let users = fetch_many(pool, internal_ids, options).await?;
+    Ok(users
+        .into_iter()
+        .map(|user| (user.internal_id, user))
+        .collect())
+}
+
+/// List users ordered by key, starting after `after`.
+///
+/// Pass the key of the last user from the previous page as `after` to get the next page.
+async fn list(
+    pool: &DbPool,
+    after: Option<InternalId>,
+    limit: i64,
+    filter: Option<&UserFilter>,
+    options: &Options,
+) -> Result<Vec<User>, Error> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut conditions = vec!["deleted_at is null".to_owned()];
+    if let Some(after) = &after {
+        params.push(after);
+        conditions.push(format!("internal_id > ${}", params.len()));
+    }
+    if let Some(filter) = filter {
+        conditions.push(filter.to_sql(&mut params));
+    }
+    if let Some(tenant) = &options.tenant {
+        params.push(tenant);
+        conditions.push(format!("organization_id = ${}", params.len()));
+    }
+    params.push(&limit);
+
+    let con = pool.get().await?;
+
+    let rows = con
+        .query(
+            format!(
+                r#"
+                select users.*, user_profiles.bio
+                from users
+                left join user_profiles using (internal_id)
+                where {}
+                order by internal_id
+                limit ${}
+                "#,
+                conditions.join(" and "),
+                params.len(),
+            )
+            .as_str(),
+            &params,
+        )
+        .await?;
+
+    rows.iter().map(|row| User::read(row, options)).collect()
+}
+
+/// Find users whose `one` or `two` match the full text `query`, ordered by key. Soft deleted users
+/// are excluded.
+///
+/// `query` uses the `websearch_to_tsquery` syntax, so `"foo bar" -baz` works.
+async fn search(
+    pool: &DbPool,
+    query: &str,
+    limit: i64,
+    options: &Options,
+) -> Result<Vec<User>, Error> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&query, &limit];
+    let tenant = tenant_condition(options, &mut params);
+
+    let con = pool.get().await?;
+
+    let rows = con
+        .query(
+            format!(
+                r#"
+                select users.*, user_profiles.bio
+                from users
+                left join user_profiles using (internal_id)
+                where deleted_at is null and search @@ websearch_to_tsquery('simple', $1){}
+                order by internal_id
+                limit $2
+                "#,
+                tenant,
+            )
+            .as_str(),
+            &params,
+        )
+        .await?;
+
+    rows.iter().map(|row| User::read(row, options)).collect()
+}
+
+/// Like `fetch` but also finds soft deleted users.
+async fn fetch_including_deleted(
+    pool: &DbPool,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<User, Error> {
+    let con = pool.get().await?;
+    query_user(&*con, FETCH_INCLUDING_DELETED_QUERY, internal_id, options)
+        .await?
+        .ok_or(Error::NotFound)
+}
+
+/// Like `fetch` but within a transaction managed by the caller.
+async fn fetch_in_transaction(
+    tx: &Transaction<'_>,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<User, Error> {
+    fetch_opt_in_transaction(tx, internal_id, options)
+        .await?
+        .ok_or(Error::NotFound)
+}
+
+/// Like `fetch_opt` but within a transaction managed by the caller.
+async fn fetch_opt_in_transaction(
+    tx: &Transaction<'_>,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<Option<User>, Error> {
+    query_user(tx, FETCH_QUERY, internal_id, options).await
+}
+
+const FETCH_QUERY: &str = r#"
+    select users.*, user_profiles.bio
+    from users
+    left join user_profiles using (internal_id)
+    where internal_id = $1 and deleted_at is null
+"#;
+
+const FETCH_INCLUDING_DELETED_QUERY: &str = r#"
+    select users.*, user_profiles.bio
+    from users
+    left join user_profiles using (internal_id)
+    where internal_id = $1
+"#;
+
+async fn query_user<C>(
+    client: &C,
+    query: &str,
+    internal_id: InternalId,
+    options: &Options,
+) -> Result<Option<User>, Error>
+where
+    C: GenericClient,
+{
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&internal_id];
+    let query = format!("{}{}", query, tenant_condition(options, &mut params));
+    let row = client.query_opt(query.as_str(), &params).await?;
+    row.map(|row| User::read(&row, options)).transpose()
+}
+
+/// ` and organization_id = $n` if `options.tenant` is set, so users in other organizations are
+/// left out. The tenant is bound as the next parameter.
+fn tenant_condition<'a>(options: &'a Options, params: &mut Vec<&'a (dyn ToSql + Sync)>) -> String {
+    match &options.tenant {
+        Some(tenant) => {
+            params.push(tenant);
+            format!(" and organization_id = ${}", params.len())
+        }
+        None => String::new(),
+    }
+}
 
-    User {
-        id: row.get("id"),
-        internal_id: row.get("internal_id"),
-        one: row.get("one"),
-        two: row.get("two"),
+impl User {
+    fn from_row(row: &Row) -> Self {
+        User {
+            id: row.get("id"),
+            internal_id: row.get("internal_id"),
+            one: row.get("one"),
+            two: row.get("two"),
+            metadata: row
+                .get::<_, Option<Json<Value>>>("metadata")
+                .map(|metadata| metadata.0),
+            deleted_at: row.get("deleted_at"),
+            status: row.get("status"),
+            locale: row.get("locale"),
+            organization_id: row.get("organization_id"),
+            bio: row.get("bio"),
+        }
+    }
+
+    /// Like `from_row` but with the fields in `options.encryption` decrypted.
+    fn read(row: &Row, options: &Options) -> Result<Self, Error> {
+        let user = User::from_row(row);
+        match &options.encryption {
+            Some(encryption) => user.decrypted(encryption),
+            None => Ok(user),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bb8_postgres::{bb8, PostgresConnectionManager};
+    use crate::test_support::{assert_noop, assert_patch_eq, assert_user};
     use serde_json::json;
     use std::process::Command;
+    use std::sync::Once;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn works() {
         let pool = db_connect().await;
 
-        let internal_id = 1;
+        let internal_id = InternalId(1);
 
         // initial insert
         let payload = json!({
@@ -127,12 +1662,12 @@ mod tests {
             "two": "1",
         });
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
 
-        let user = fetch(&pool, internal_id).await;
-        assert_eq!(user.internal_id, 1);
-        assert_eq!(user.one.as_deref(), Some("1"));
-        assert_eq!(user.two.as_deref(), Some("1"));
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(outcome, Outcome::Inserted { id: user.id });
+        assert_eq!(user.internal_id, InternalId(1));
+        assert_user!(user, { "one": "1", "two": "1" });
 
         // updating both
         let payload = json!({
@@ -140,77 +1675,2749 @@ mod tests {
             "two": "2",
         });
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one", "two"]
+            }
+        );
 
-        let user = fetch(&pool, internal_id).await;
-        assert_eq!(user.internal_id, 1);
-        assert_eq!(user.one.as_deref(), Some("2"));
-        assert_eq!(user.two.as_deref(), Some("2"));
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.internal_id, InternalId(1));
+        assert_user!(user, { "one": "2", "two": "2" });
 
         // updating one
         let payload = json!({
             "one": "3",
         });
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one"]
+            }
+        );
 
-        let user = fetch(&pool, internal_id).await;
-        assert_eq!(user.one.as_deref(), Some("3"));
-        assert_eq!(user.two.as_deref(), Some("2"));
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_user!(user, { "one": "3", "two": "2" });
 
         // updating the other
         let payload = json!({
             "two": "3",
         });
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["two"]
+            }
+        );
 
-        let user = fetch(&pool, internal_id).await;
-        assert_eq!(user.one.as_deref(), Some("3"));
-        assert_eq!(user.two.as_deref(), Some("3"));
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_user!(user, { "one": "3", "two": "3" });
 
         // updating neither
         let payload = json!({});
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_noop!(outcome);
 
-        let user = fetch(&pool, internal_id).await;
-        assert_eq!(user.one.as_deref(), Some("3"));
-        assert_eq!(user.two.as_deref(), Some("3"));
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_user!(user, { "one": "3", "two": "3" });
 
         // setting one to `null`
         let payload = json!({ "one": null });
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one"]
+            }
+        );
 
-        let user = fetch(&pool, internal_id).await;
-        assert_eq!(user.one.as_deref(), None, "one == null");
-        assert_eq!(user.two.as_deref(), Some("3"));
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_user!(user, { "one": null, "two": "3" });
 
         // change one, set two to null
         let payload = json!({ "one": "1", "two": null });
         let payload = serde_json::from_value::<Update>(payload).unwrap();
-        payload.insert_or_update(internal_id, &pool).await;
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one", "two"]
+            }
+        );
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_user!(user, { "one": "1", "two": null });
+    }
+
+    #[tokio::test]
+    async fn json_fields() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(2);
+
+        let payload = json!({ "metadata": { "theme": "dark" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.metadata, Some(json!({ "theme": "dark" })));
+
+        // missing leaves the document untouched
+        let payload = json!({ "one": "1" });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.metadata, Some(json!({ "theme": "dark" })));
+
+        // present value overwrites the whole document
+        let payload = json!({ "metadata": { "locale": "da" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.metadata, Some(json!({ "locale": "da" })));
+
+        // null clears it
+        let payload = json!({ "metadata": null });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.metadata, None);
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn cli_apply() {
+        let pool = db_connect().await;
+
+        let internal_id = 31;
+
+        let outcome = cli::apply(&pool, "users", internal_id, r#"{ "one": "1" }"#)
+            .await
+            .unwrap();
+        assert_eq!(outcome, "inserted");
+
+        let outcome = cli::apply(
+            &pool,
+            "users",
+            internal_id,
+            r#"{ "one": "2", "two": null }"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, "updated one");
+
+        let err = cli::apply(&pool, "accounts", internal_id, "{}")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig("table")));
+
+        let err = cli::apply(&pool, "users", internal_id, r#"{ "one": 1 }"#)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+        assert!(err.to_string().starts_with("couldn't parse patch: "));
+    }
+
+    #[tokio::test]
+    async fn memory_store_matches_postgres() {
+        use store::{MemoryStore, UserStore};
+
+        async fn run(store: &dyn UserStore, internal_id: InternalId) -> (Vec<Outcome>, Value) {
+            let mut outcomes = Vec::new();
+            for payload in [
+                json!({ "one": "1", "metadata": { "a": 1 } }),
+                json!({ "one": "1", "two": "2", "locale": "da" }),
+                json!({ "one": "1" }),
+                json!({ "one": null, "locale": null, "profile": { "bio": "hi" } }),
+            ] {
+                let payload = serde_json::from_value::<Update>(payload).unwrap();
+                // each store generates ids its own way
+                let outcome = match store.insert_or_update(internal_id, &payload).await.unwrap() {
+                    Outcome::Inserted { .. } => Outcome::Inserted { id: 0 },
+                    outcome => outcome,
+                };
+                outcomes.push(outcome);
+            }
+
+            let mut user = serde_json::to_value(store.fetch(internal_id).await.unwrap()).unwrap();
+            user["id"] = json!(null);
+            (outcomes, user)
+        }
+
+        let postgres: Box<dyn UserStore> = Box::new(db_connect().await);
+        let memory: Box<dyn UserStore> = Box::new(MemoryStore::default());
 
-        let user = fetch(&pool, internal_id).await;
+        assert_eq!(
+            run(&*postgres, InternalId(40)).await,
+            run(&*memory, InternalId(40)).await
+        );
+        assert!(matches!(
+            memory.fetch(InternalId(41)).await.unwrap_err(),
+            Error::NotFound
+        ));
+    }
+
+    #[cfg(feature = "moka")]
+    #[tokio::test]
+    async fn cached_fetch() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(39);
+        let cache = Arc::new(cache::MokaCache::new(100, Duration::from_secs(1)));
+        let options = Options {
+            cache: Some(cache.clone()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        let user = cache::fetch_cached(&pool, &*cache, internal_id, &options)
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("1"));
+
+        // writes that bypass the cache aren't seen
+        let con = pool.get().await.unwrap();
+        con.execute(
+            "update users set one = '2' where internal_id = $1",
+            &[&internal_id],
+        )
+        .await
+        .unwrap();
+        drop(con);
+        let user = cache::fetch_cached(&pool, &*cache, internal_id, &options)
+            .await
+            .unwrap();
         assert_eq!(user.one.as_deref(), Some("1"));
-        assert_eq!(user.two.as_deref(), None);
+
+        // but patches invalidate it
+        let payload = serde_json::from_value::<Update>(json!({ "one": "3" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        let user = cache::fetch_cached(&pool, &*cache, internal_id, &options)
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("3"));
+
+        // and stale users expire
+        let con = pool.get().await.unwrap();
+        con.execute(
+            "update users set one = '4' where internal_id = $1",
+            &[&internal_id],
+        )
+        .await
+        .unwrap();
+        drop(con);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let user = cache::fetch_cached(&pool, &*cache, internal_id, &options)
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("4"));
     }
 
-    async fn db_connect() -> DbPool {
-        assert!(Command::new("./setup").status().unwrap().success());
+    #[cfg(feature = "moka")]
+    #[tokio::test]
+    async fn cached_fetch_with_options() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(70);
+        let cache = Arc::new(cache::MokaCache::new(100, Duration::from_secs(60)));
+        let options = Options {
+            cache: Some(cache.clone()),
+            encryption: Some(
+                encrypt::Encryption::new(Arc::new(encrypt::tests::Reversed)).field("one", "pii"),
+            ),
+            tenant: Some(70),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "secret" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        // misses and hits both return the decrypted value
+        for _ in 0..2 {
+            let user = cache::fetch_cached(&pool, &*cache, internal_id, &options)
+                .await
+                .unwrap();
+            assert_eq!(user.one.as_deref(), Some("secret"));
+        }
+
+        // the cached user isn't found from another organization
+        let other = Options {
+            tenant: Some(71),
+            ..Options::default()
+        };
+        let err = cache::fetch_cached(&pool, &*cache, internal_id, &other)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+
+        // nor when it isn't cached
+        cache::UserCache::invalidate(&*cache, internal_id);
+        let err = cache::fetch_cached(&pool, &*cache, internal_id, &other)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+    }
+
+    #[tokio::test]
+    async fn hstore_fields() {
+        let pool = db_connect().await;
+        let con = pool.get().await.unwrap();
+
+        con.batch_execute(
+            r#"
+            create temporary table settings (id int primary key, attrs hstore);
+            insert into settings (id, attrs) values (1, 'a => 1, b => 2'), (2, null);
+            "#,
+        )
+        .await
+        .unwrap();
+
+        let patch = json!({ "b": null, "c": "3" });
+        let patch = serde_json::from_value::<hstore::HstorePatch>(patch).unwrap();
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let attrs = patch.to_sql("attrs", &mut params);
+        con.execute(
+            format!("update settings set attrs = {}", attrs).as_str(),
+            &params,
+        )
+        .await
+        .unwrap();
+
+        let rows = con
+            .query("select attrs from settings order by id", &[])
+            .await
+            .unwrap();
+        let attrs = rows
+            .iter()
+            .map(|row| row.get::<_, HashMap<String, Option<String>>>("attrs"))
+            .collect::<Vec<_>>();
+
+        let expected = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), Some(value.to_string())))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(attrs[0], expected(&[("a", "1"), ("c", "3")]));
+        assert_eq!(attrs[1], expected(&[("c", "3")]));
+    }
+
+    #[tokio::test]
+    async fn concurrent_inserts() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(3);
+
+        let tasks = (0..10)
+            .map(|n| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let payload = json!({ "one": n.to_string() });
+                    let payload = serde_json::from_value::<Update>(payload).unwrap();
+                    payload.insert_or_update(internal_id, &pool).await.unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert!(user.one.is_some());
+    }
+
+    #[tokio::test]
+    async fn concurrent_inserts_with_isolation_levels() {
+        let pool = db_connect().await;
+
+        for (internal_id, isolation_level) in [
+            (4, IsolationLevel::RepeatableRead),
+            (5, IsolationLevel::Serializable),
+        ] {
+            let tasks = (0..10)
+                .map(|n| {
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        let payload = json!({ "one": n.to_string() });
+                        let payload = serde_json::from_value::<Update>(payload).unwrap();
+                        let options = Options {
+                            isolation_level,
+                            ..Options::default()
+                        };
+                        payload
+                            .insert_or_update_with_options(InternalId(internal_id), &pool, &options)
+                            .await
+                            .unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            let user = fetch(&pool, InternalId(internal_id)).await.unwrap();
+            assert!(user.one.is_some());
+        }
+    }
 
-        let mut config = tokio_postgres::config::Config::new();
+    #[tokio::test]
+    async fn external_transaction() {
+        let pool = db_connect().await;
+        let mut con = pool.get().await.unwrap();
+
+        let internal_id = InternalId(6);
+
+        // rolled back along with the rest of the transaction
+        let tx = con.transaction().await.unwrap();
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update_in_transaction(internal_id, &tx, &Options::default())
+            .await
+            .unwrap();
+        let user = fetch_in_transaction(&tx, internal_id, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("1"));
+        tx.rollback().await.unwrap();
+
+        let tx = con.transaction().await.unwrap();
+        let err = fetch_in_transaction(&tx, internal_id, &Options::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+        assert!(
+            fetch_opt_in_transaction(&tx, internal_id, &Options::default())
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // committed along with the rest of the transaction
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload
+            .insert_or_update_in_transaction(internal_id, &tx, &Options::default())
+            .await
+            .unwrap();
+        tx.execute("select 1", &[]).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn session_settings() {
+        let pool = db_connect().await;
+        let mut con = pool.get().await.unwrap();
+
+        let options = Options {
+            settings: vec![("app.tenant_id".to_owned(), "1".to_owned())],
+            ..Options::default()
+        };
+
+        let tx = con.transaction().await.unwrap();
+        apply_session(&tx, &options).await.unwrap();
+        let tenant_id: String = tx
+            .query_one("select current_setting('app.tenant_id')", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(tenant_id, "1");
+        tx.commit().await.unwrap();
+
+        // settings don't outlive the transaction
+        let tenant_id: Option<String> = con
+            .query_one("select current_setting('app.tenant_id', true)", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_ne!(tenant_id.as_deref(), Some("1"));
+        drop(con);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        let options = Options {
+            role: Some("no_such_role".to_owned()),
+            ..Options::default()
+        };
+        let err = payload
+            .insert_or_update_with_options(InternalId(27), &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Postgres(_)));
+    }
+
+    #[tokio::test]
+    async fn tenant_scoping() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(28);
+        let options = Options {
+            tenant: Some(4),
+            ..Options::default()
+        };
+
+        // inserts belong to the tenant
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.organization_id, Some(4));
+
+        // users can't be moved to another tenant
+        let payload = serde_json::from_value::<Update>(json!({ "organization_id": 5 })).unwrap();
+        let err = payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::WrongTenant));
+
+        // nor patched by another tenant
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let options = Options {
+            tenant: Some(5),
+            ..Options::default()
+        };
+        let err = payload
+            .clone()
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::WrongTenant));
+
+        // nor read or bulk updated by another tenant
+        let err = fetch_with_options(&pool, internal_id, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+        let filter = UserFilter::OrganizationId(4);
+        assert!(list(&pool, None, 100, Some(&filter), &options)
+            .await
+            .unwrap()
+            .is_empty());
+        let err =
+            projection::fetch_columns(&pool, internal_id, &[projection::UserColumn::One], &options)
+                .await
+                .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+        let updated = payload
+            .update_where(&filter, &pool, &options)
+            .await
+            .unwrap();
+        assert_eq!(updated, 0);
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("1"));
+        assert_eq!(user.organization_id, Some(4));
+    }
+
+    #[tokio::test]
+    async fn patch_log() {
+        let pool = db_connect().await;
 
-        config.host("localhost");
-        config.user("david.pedersen");
-        config.dbname("testing");
+        let internal_id = InternalId(29);
+        let options = Options {
+            log: Some(PatchMeta {
+                actor: Some("bob".to_owned()),
+                request_id: Some("abc".to_owned()),
+            }),
+            ..Options::default()
+        };
+
+        let payload = json!({ "one": "1", "two": null, "profile": { "bio": "hi" } });
+        serde_json::from_value::<Update>(payload.clone())
+            .unwrap()
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        let con = pool.get().await.unwrap();
+        let row = con
+            .query_one(
+                "select patch, actor, request_id from patches where internal_id = $1",
+                &[&internal_id],
+            )
+            .await
+            .unwrap();
+        let patch: Json<Value> = row.get("patch");
+        assert_eq!(patch.0, payload);
+        assert_eq!(
+            row.get::<_, Option<String>>("actor").as_deref(),
+            Some("bob")
+        );
+        assert_eq!(
+            row.get::<_, Option<String>>("request_id").as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_patches() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(30);
+        let options = Options {
+            log: Some(PatchMeta::default()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1", "two": "1" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        let snapshot = fetch(&pool, internal_id).await.unwrap();
+        let since = std::time::SystemTime::now();
+
+        for payload in [
+            json!({ "one": "2", "locale": "da" }),
+            json!({ "two": null, "profile": { "bio": "hi" } }),
+            json!({ "locale": null }),
+        ] {
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            payload
+                .insert_or_update_with_options(internal_id, &pool, &options)
+                .await
+                .unwrap();
+        }
+
+        let current = serde_json::to_value(fetch(&pool, internal_id).await.unwrap()).unwrap();
+        let rebuilt = patch_log::rebuild(&pool, snapshot, since, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(serde_json::to_value(rebuilt).unwrap(), current);
+
+        // simulate restoring a backup taken at `since`
+        let con = pool.get().await.unwrap();
+        con.execute(
+            "update users set one = '1', two = '1', locale = 'en' where internal_id = $1",
+            &[&internal_id],
+        )
+        .await
+        .unwrap();
+        con.execute(
+            "delete from user_profiles where internal_id = $1",
+            &[&internal_id],
+        )
+        .await
+        .unwrap();
+        drop(con);
 
-        let manager = PostgresConnectionManager::new(config, tokio_postgres::NoTls);
+        patch_log::reapply(&pool, internal_id, since, &Options::default())
+            .await
+            .unwrap();
+        let restored = serde_json::to_value(fetch(&pool, internal_id).await.unwrap()).unwrap();
+        assert_eq!(restored, current);
+    }
+
+    #[tokio::test]
+    async fn rebuild_matches_fetch() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(67);
+        let encryption =
+            encrypt::Encryption::new(Arc::new(encrypt::tests::Reversed)).field("one", "pii");
+        let logged = Options {
+            log: Some(PatchMeta::default()),
+            encryption: Some(encryption.clone()),
+            ..Options::default()
+        };
+        let options = Options {
+            encryption: Some(encryption),
+            ..Options::default()
+        };
+
+        let payload =
+            serde_json::from_value::<Update>(json!({ "one": "1", "locale": "da" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &logged)
+            .await
+            .unwrap();
+        let snapshot = fetch_with_options(&pool, internal_id, &options)
+            .await
+            .unwrap();
+        let since = std::time::SystemTime::now();
+
+        for payload in [
+            json!({ "locale": null }),
+            json!({ "one": "2", "status": "suspended" }),
+            json!({ "locale": "de", "metadata": { "a": 1 } }),
+            json!({ "locale": null, "status": null }),
+        ] {
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            payload
+                .insert_or_update_with_options(internal_id, &pool, &logged)
+                .await
+                .unwrap();
+        }
+
+        // `null` resets the locale to its default in both, and the logged patches are decrypted
+        let current = fetch_with_options(&pool, internal_id, &options)
+            .await
+            .unwrap();
+        assert_user!(current.clone(), { "one": "2", "locale": "en", "status": null });
+        let rebuilt = patch_log::rebuild(&pool, snapshot, since, &options)
+            .await
+            .unwrap();
+        let current = serde_json::to_value(current).unwrap();
+        assert_eq!(serde_json::to_value(rebuilt).unwrap(), current);
+
+        // reapplying stores the patches again, so it refuses to log them
+        let err = patch_log::reapply(&pool, internal_id, since, &logged)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig("log")));
+
+        let con = pool.get().await.unwrap();
+        con.execute(
+            r#"
+            update users
+            set one = 'pii:MQ==', locale = 'da', status = 'active', metadata = null
+            where internal_id = $1
+            "#,
+            &[&internal_id],
+        )
+        .await
+        .unwrap();
+        drop(con);
 
-        bb8::Pool::builder()
+        patch_log::reapply(&pool, internal_id, since, &options)
+            .await
+            .unwrap();
+        let restored = fetch_with_options(&pool, internal_id, &options)
+            .await
+            .unwrap();
+        assert_eq!(serde_json::to_value(restored).unwrap(), current);
+    }
+
+    #[tokio::test]
+    async fn full_patch_from_user() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(33);
+
+        let payload = json!({ "one": "1", "two": "1", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let mut user = fetch(&pool, internal_id).await.unwrap();
+        user.one = Some("2".to_owned());
+        user.two = None;
+        user.bio = None;
+
+        let outcome = Update::from(user)
+            .insert_or_update(internal_id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one", "two", "profile.bio"]
+            }
+        );
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.two, None);
+        assert_eq!(user.bio, None);
+    }
+
+    #[tokio::test]
+    async fn out_of_order_patches() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(37);
+        let now = std::time::SystemTime::now();
+        let at = |secs| Options {
+            source_updated_at: Some(now + std::time::Duration::from_secs(secs)),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &at(10))
+            .await
+            .unwrap();
+
+        // an older event arriving late is skipped
+        let payload = serde_json::from_value::<Update>(json!({ "one": "0" })).unwrap();
+        let outcome = payload
+            .insert_or_update_with_options(internal_id, &pool, &at(5))
+            .await
+            .unwrap();
+        assert_eq!(outcome, Outcome::Noop);
+
+        // a newer one that changes nothing still moves the timestamp forward
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &at(20))
+            .await
+            .unwrap();
+        let payload = serde_json::from_value::<Update>(json!({ "one": "0" })).unwrap();
+        let outcome = payload
+            .insert_or_update_with_options(internal_id, &pool, &at(15))
+            .await
+            .unwrap();
+        assert_eq!(outcome, Outcome::Noop);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &at(30))
+            .await
+            .unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn fetch_some_columns() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(38);
+
+        let payload = json!({ "one": "1", "two": "2", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let columns = [projection::UserColumn::One, projection::UserColumn::Bio];
+        let user = projection::fetch_columns(&pool, internal_id, &columns, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(Value::Object(user), json!({ "one": "1", "bio": "hi" }));
+
+        let err = projection::fetch_columns(&pool, InternalId(0), &columns, &Options::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+    }
+
+    #[tokio::test]
+    async fn full_text_search() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(42);
+
+        let payload = json!({ "one": "quick brown fox", "two": "lazy dog" });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let keys = |users: Vec<User>| {
+            users
+                .iter()
+                .map(|user| user.internal_id.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(
+                search(&pool, "fox dog", 10, &Options::default())
+                    .await
+                    .unwrap()
+            ),
+            vec![42]
+        );
+
+        // patching only `two` keeps `one` searchable and replaces the old `two`
+        let payload = serde_json::from_value::<Update>(json!({ "two": "sleepy cat" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            keys(
+                search(&pool, "fox cat", 10, &Options::default())
+                    .await
+                    .unwrap()
+            ),
+            vec![42]
+        );
+        assert!(search(&pool, "dog", 10, &Options::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn filter_sql() {
+        let filter = UserFilter::OrganizationId(1)
+            .and(UserFilter::Status(UserStatus::Active))
+            .and(UserFilter::Locale("da".to_owned()).or(UserFilter::NoOrganization));
+
+        let mut params = Vec::new();
+        assert_eq!(
+            filter.to_sql(&mut params),
+            "(organization_id = $1 and status = $2 and (locale = $3 or organization_id is null))"
+        );
+        assert_eq!(params.len(), 3);
+
+        let mut params = Vec::new();
+        assert_eq!(UserFilter::Or(Vec::new()).to_sql(&mut params), "false");
+    }
+
+    #[tokio::test]
+    async fn list_with_combined_filter() {
+        let pool = db_connect().await;
+
+        for (internal_id, locale) in [(34, "da"), (35, "en"), (36, "da")] {
+            let payload = json!({ "organization_id": 6, "locale": locale });
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            payload
+                .insert_or_update(InternalId(internal_id), &pool)
+                .await
+                .unwrap();
+        }
+
+        let filter = UserFilter::OrganizationId(6).and(UserFilter::Locale("da".to_owned()));
+        let users = list(&pool, None, 10, Some(&filter), &Options::default())
+            .await
+            .unwrap();
+        let keys = users
+            .iter()
+            .map(|user| user.internal_id.0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![34, 36]);
+    }
+
+    #[tokio::test]
+    async fn replace_user() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(44);
+
+        let payload = json!({ "one": "1", "two": "1", "locale": "da", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let payload = json!({ "one": "2", "two": "1" });
+        let outcome = serde_json::from_value::<complete::Replace>(payload)
+            .unwrap()
+            .into_patch()
+            .insert_or_update(internal_id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one", "locale", "profile.bio"]
+            }
+        );
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.locale, "en");
+        assert_eq!(user.bio, None);
+    }
+
+    #[tokio::test]
+    async fn cross_field_rules() {
+        let pool = db_connect().await;
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        let options = Options {
+            rules: vec![Rule::Requires("one", "two")],
+            ..Options::default()
+        };
+        let err = payload
+            .clone()
+            .insert_or_update_with_options(InternalId(45), &pool, &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid patch: `one` requires `two`");
+        assert!(matches!(
+            fetch(&pool, InternalId(45)).await.unwrap_err(),
+            Error::NotFound
+        ));
+
+        // misspelled fields would otherwise always be missing
+        let options = Options {
+            rules: vec![Rule::Exclusive("one", "tow")],
+            ..Options::default()
+        };
+        let err = payload
+            .insert_or_update_with_options(InternalId(45), &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig("tow")));
+    }
+
+    /// Caches nothing, recording what was invalidated.
+    #[derive(Default)]
+    struct Invalidated(std::sync::Mutex<Vec<InternalId>>);
+
+    impl cache::UserCache for Invalidated {
+        fn get(&self, _: InternalId) -> Option<User> {
+            None
+        }
+
+        fn insert(&self, _: User) {}
+
+        fn invalidate(&self, internal_id: InternalId) {
+            self.0.lock().unwrap().push(internal_id);
+        }
+    }
+
+    struct UniqueOne;
+
+    impl validate::Validator for UniqueOne {
+        fn field(&self) -> &'static str {
+            "one"
+        }
+
+        fn validate<'a>(
+            &'a self,
+            internal_id: InternalId,
+            patch: &'a Update,
+            tx: &'a Transaction<'_>,
+        ) -> store::BoxFuture<'a, Result<Option<String>, Error>> {
+            Box::pin(async move {
+                let taken = tx
+                    .query_opt(
+                        "select 1 from users where one = $1 and internal_id <> $2",
+                        &[&patch.one.clone().flatten(), &internal_id],
+                    )
+                    .await?
+                    .is_some();
+                Ok(taken.then(|| "is already taken".to_owned()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn async_validators() {
+        let pool = db_connect().await;
+        let options = Options {
+            validators: vec![Arc::new(UniqueOne)],
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "taken" })).unwrap();
+        payload
+            .clone()
+            .insert_or_update_with_options(InternalId(46), &pool, &options)
+            .await
+            .unwrap();
+
+        // the row's own value doesn't count as taken
+        payload
+            .clone()
+            .insert_or_update_with_options(InternalId(46), &pool, &options)
+            .await
+            .unwrap();
+
+        let err = payload
+            .insert_or_update_with_options(InternalId(47), &pool, &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid patch: `one` is already taken");
+
+        // validators only run for fields in the patch
+        let payload = serde_json::from_value::<Update>(json!({ "two": "2" })).unwrap();
+        payload
+            .insert_or_update_with_options(InternalId(47), &pool, &options)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn normalize_before_validation() {
+        let pool = db_connect().await;
+        let pipeline = normalize::Pipeline::new()
+            .then(normalize::Step::Trim)
+            .then(normalize::Step::Lowercase);
+        let options = Options {
+            validators: vec![Arc::new(UniqueOne)],
+            normalize: vec![("one", pipeline)],
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "Shared" })).unwrap();
+        payload
+            .insert_or_update_with_options(InternalId(48), &pool, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            fetch(&pool, InternalId(48)).await.unwrap().one.as_deref(),
+            Some("shared")
+        );
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": " SHARED " })).unwrap();
+        let err = payload
+            .insert_or_update_with_options(InternalId(49), &pool, &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid patch: `one` is already taken");
+    }
+
+    #[tokio::test]
+    async fn diff_of_normalized_patch() {
+        let pool = db_connect().await;
+        let options = Options {
+            normalize: vec![(
+                "locale",
+                normalize::Pipeline::new().then(normalize::Step::Lowercase),
+            )],
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "locale": "DA" })).unwrap();
+        let mut con = pool.get().await.unwrap();
+        let tx = con.transaction().await.unwrap();
+        let mut applied = Applied::default();
+        let outcome = payload
+            .apply_in_transaction(InternalId(66), &tx, &options, &mut applied)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // the event shows the value that was written, not the one that was received
+        let changes = diff::changes(
+            InternalId(66),
+            &outcome,
+            applied.before.as_ref(),
+            applied.patch.as_ref().unwrap(),
+            &[],
+        );
+        assert_eq!(
+            Value::Object(changes),
+            json!({ "locale": { "old": "en", "new": "da" } })
+        );
+    }
+
+    #[cfg(feature = "load")]
+    #[tokio::test]
+    async fn load_driver() {
+        let pool = db_connect().await;
+
+        let generator = load::PatchGenerator {
+            organizations: 100..104,
+            ..load::PatchGenerator::default()
+        };
+        let report = load::drive(&pool, generator, 1000..1004, 40, 8).await;
+        assert_eq!(report.failed, 0);
+        assert!(report.inserted <= 4);
+        assert_eq!(report.inserted + report.updated + report.noop, 40);
+    }
+
+    #[tokio::test]
+    async fn explain_plans() {
+        let pool = db_connect().await;
+        let explain = explain::Explain::default();
+        let options = Options {
+            explain: Some(explain.clone()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        let outcome = payload
+            .clone()
+            .insert_or_update_with_options(InternalId(50), &pool, &options)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Inserted { .. }));
+
+        let plans = explain.plans();
+        assert_eq!(plans.len(), 2);
+        // the lookup shows whether the conflict target is indexed
+        assert!(plans[0].starts_with("LockRows"), "{}", plans[0]);
+        assert!(plans[0].contains("users_internal_id"), "{}", plans[0]);
+        assert!(plans[1].starts_with("Insert on users"), "{}", plans[1]);
+
+        // plans of earlier patches are cleared
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload
+            .insert_or_update_with_options(InternalId(50), &pool, &options)
+            .await
+            .unwrap();
+
+        let plans = explain.plans();
+        assert_eq!(plans.len(), 2);
+        assert!(plans[0].starts_with("LockRows"), "{}", plans[0]);
+        assert!(plans[1].starts_with("Update on users"), "{}", plans[1]);
+        assert!(plans.iter().all(|plan| plan.contains("actual time")));
+
+        // the explained statements were rolled back and only the real ones applied
+        assert_user!(fetch(&pool, InternalId(50)).await.unwrap(), { "one": "2" });
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys() {
+        let pool = db_connect().await;
+        let options = Options {
+            idempotency_key: Some("delivery-1".to_owned()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        let inserted = payload
+            .clone()
+            .insert_or_update_with_options(InternalId(60), &pool, &options)
+            .await
+            .unwrap();
+        assert!(matches!(inserted, Outcome::Inserted { .. }));
+
+        // a redelivery returns the first outcome
+        let replayed = payload
+            .insert_or_update_with_options(InternalId(60), &pool, &options)
+            .await
+            .unwrap();
+        assert_eq!(replayed, inserted);
+
+        let options = Options {
+            idempotency_key: Some("delivery-2".to_owned()),
+            ..options
+        };
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2", "two": null })).unwrap();
+        let updated = payload
+            .clone()
+            .insert_or_update_with_options(InternalId(60), &pool, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            updated,
+            Outcome::Updated {
+                changed_fields: vec!["one"]
+            }
+        );
+
+        // the replay isn't validated again
+        let rules = vec![Rule::RequiresValue("one", "two")];
+        let err = payload
+            .clone()
+            .insert_or_update_with_options(
+                InternalId(60),
+                &pool,
+                &Options {
+                    rules: rules.clone(),
+                    ..Options::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Invalid(_)));
+        let replayed = payload
+            .insert_or_update_with_options(
+                InternalId(60),
+                &pool,
+                &Options {
+                    idempotency_key: Some("delivery-2".to_owned()),
+                    rules,
+                    ..Options::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(replayed, updated);
+
+        // reusing the key for a different patch fails and applies nothing
+        let payload = serde_json::from_value::<Update>(json!({ "one": "3" })).unwrap();
+        let err = payload
+            .clone()
+            .insert_or_update_with_options(InternalId(60), &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::IdempotencyKeyReused));
+        assert_user!(fetch(&pool, InternalId(60)).await.unwrap(), { "one": "2" });
+
+        // batches would share the key between patches
+        let err = batch::insert_or_update_many(
+            &pool,
+            &[(InternalId(60), payload)],
+            &options,
+            batch::BatchMode::Abort,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig("idempotency_key")));
+    }
+
+    #[tokio::test]
+    async fn encrypted_fields() {
+        let pool = db_connect().await;
+        let encryption = encrypt::Encryption::new(Arc::new(encrypt::tests::Reversed))
+            .field("one", "pii")
+            .field("profile.bio", "pii");
+        let options = Options {
+            encryption: Some(encryption.clone()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({
+            "one": "secret",
+            "two": "plain",
+            "organization_id": 62,
+            "profile": { "bio": "private" },
+        }))
+        .unwrap();
+        payload
+            .clone()
+            .insert_or_update_with_options(InternalId(62), &pool, &options)
+            .await
+            .unwrap();
+
+        // stored encrypted, `two` isn't encrypted
+        assert_user!(
+            fetch(&pool, InternalId(62)).await.unwrap(),
+            { "one": "pii:dGVyY2Vz", "two": "plain", "bio": "pii:ZXRhdmlycA==" }
+        );
+        assert_user!(
+            fetch_with_options(&pool, InternalId(62), &options).await.unwrap(),
+            { "one": "secret", "two": "plain", "bio": "private" }
+        );
+
+        // compared with the decrypted values
+        let outcome = payload
+            .insert_or_update_with_options(InternalId(62), &pool, &options)
+            .await
+            .unwrap();
+        assert_noop!(outcome);
+
+        // every read given the options decrypts
+        let users = list(
+            &pool,
+            None,
+            100,
+            Some(&UserFilter::OrganizationId(62)),
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(users[0].one.as_deref(), Some("secret"));
+        let columns = [projection::UserColumn::One, projection::UserColumn::Bio];
+        let user = projection::fetch_columns(&pool, InternalId(62), &columns, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            Value::Object(user),
+            json!({ "one": "secret", "bio": "private" })
+        );
+
+        // the ETag of what clients read matches
+        let user = fetch_with_options(&pool, InternalId(62), &options)
+            .await
+            .unwrap();
+        let options = Options {
+            if_match: Some(user.etag()),
+            encryption: Some(encryption.clone()),
+            ..Options::default()
+        };
+        let outcome = serde_json::from_value::<Update>(json!({ "two": "changed" }))
+            .unwrap()
+            .insert_or_update_with_options(InternalId(62), &pool, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["two"]
+            }
+        );
+
+        // and `update_where` encrypts
+        serde_json::from_value::<Update>(json!({ "one": "bulk" }))
+            .unwrap()
+            .update_where(&UserFilter::OrganizationId(62), &pool, &options)
+            .await
+            .unwrap();
+        assert_user!(fetch(&pool, InternalId(62)).await.unwrap(), { "one": "pii:a2x1Yg==" });
+        assert_user!(
+            fetch_with_options(&pool, InternalId(62), &options).await.unwrap(),
+            { "one": "bulk" }
+        );
+    }
+
+    #[tokio::test]
+    async fn field_policy() {
+        let pool = db_connect().await;
+        let policy = Arc::new(
+            access::FieldAccess::default()
+                .read(&["two"])
+                .write(&["one"]),
+        );
+        let options = Options {
+            policy: Some(policy.clone()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1", "two": "2" })).unwrap();
+        let err = payload
+            .insert_or_update_with_options(InternalId(63), &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Forbidden(ref fields) if *fields == ["two"]));
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update_with_options(InternalId(63), &pool, &options)
+            .await
+            .unwrap();
+
+        let user = access::fetch_masked(&pool, InternalId(63), policy.as_ref(), &options)
+            .await
+            .unwrap();
+        assert_eq!(Value::Object(user), json!({ "one": "1", "two": null }));
+    }
+
+    #[tokio::test]
+    async fn erase_personal_data() {
+        let pool = db_connect().await;
+        let options = Options {
+            history: true,
+            log: Some(PatchMeta::default()),
+            ..Options::default()
+        };
+
+        let payload = serde_json::from_value::<Update>(json!({
+            "one": "name",
+            "two": "kept",
+            "organization_id": 64,
+            "profile": { "bio": "about me" },
+        }))
+        .unwrap();
+        payload
+            .insert_or_update_with_options(InternalId(64), &pool, &options)
+            .await
+            .unwrap();
+
+        let meta = PatchMeta {
+            actor: Some("dpo".to_owned()),
+            request_id: None,
+        };
+        let cache = Arc::new(Invalidated::default());
+        let options = Options {
+            cache: Some(cache.clone()),
+            tenant: Some(64),
+            ..Options::default()
+        };
+        let outcome = erase::erase(
+            &pool,
+            InternalId(64),
+            &["one", "profile.bio"],
+            &meta,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["one", "profile.bio"]
+            }
+        );
+        assert_eq!(*cache.0.lock().unwrap(), vec![InternalId(64)]);
+
+        assert_user!(
+            fetch(&pool, InternalId(64)).await.unwrap(),
+            { "one": null, "two": "kept", "bio": null }
+        );
+
+        let con = pool.get().await.unwrap();
+        let history = con
+            .query(
+                "select one, bio from users_history where internal_id = $1",
+                &[&InternalId(64)],
+            )
+            .await
+            .unwrap();
+        assert!(!history.is_empty());
+        assert!(history.iter().all(|row| {
+            row.get::<_, Option<String>>("one").is_none()
+                && row.get::<_, Option<String>>("bio").is_none()
+        }));
+
+        let patches = con
+            .query(
+                "select patch, actor from patches where internal_id = $1 order by patch_id",
+                &[&InternalId(64)],
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| {
+                let Json(patch) = row.get::<_, Json<Value>>("patch");
+                (patch, row.get::<_, Option<String>>("actor"))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            patches,
+            vec![
+                (
+                    json!({ "two": "kept", "organization_id": 64, "profile": {} }),
+                    None
+                ),
+                (
+                    json!({ "one": null, "profile": { "bio": null } }),
+                    Some("dpo".to_owned())
+                ),
+            ]
+        );
+
+        let err = erase::erase(&pool, InternalId(64), &["status"], &meta, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig("status")));
+
+        let options = Options {
+            tenant: Some(65),
+            ..Options::default()
+        };
+        let err = erase::erase(&pool, InternalId(64), &["two"], &meta, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::WrongTenant));
+        assert_user!(fetch(&pool, InternalId(64)).await.unwrap(), { "two": "kept" });
+    }
+
+    #[tokio::test]
+    async fn cancelled_patches_roll_back() {
+        let pool = db_connect().await;
+
+        let payload = serde_json::from_value::<Update>(json!({
+            "one": "before",
+            "profile": { "bio": "before" },
+        }))
+        .unwrap();
+        payload
+            .insert_or_update(InternalId(51), &pool)
+            .await
+            .unwrap();
+
+        // hold the profile lock so the patch blocks after it has updated `users`
+        let mut con = pool.get().await.unwrap();
+        let tx = con.transaction().await.unwrap();
+        tx.execute(
+            "select 1 from user_profiles where internal_id = 51 for update",
+            &[],
+        )
+        .await
+        .unwrap();
+
+        let payload = serde_json::from_value::<Update>(json!({
+            "one": "cancelled",
+            "profile": { "bio": "cancelled" },
+        }))
+        .unwrap();
+        tokio::select! {
+            _ = payload.insert_or_update(InternalId(51), &pool) => panic!("patch should be blocked"),
+            _ = tokio::time::sleep(Duration::from_millis(200)) => {}
+        }
+        tx.commit().await.unwrap();
+
+        // the cancelled transaction rolled back and no longer holds the `users` row lock
+        let payload = serde_json::from_value::<Update>(json!({ "two": "after" })).unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            payload.insert_or_update(InternalId(51), &pool),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_user!(
+            fetch(&pool, InternalId(51)).await.unwrap(),
+            { "one": "before", "two": "after", "bio": "before" }
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_partial_failures() {
+        use batch::{insert_or_update_many, BatchMode};
+
+        let pool = db_connect().await;
+        let options = Options {
+            rules: vec![Rule::Requires("one", "two")],
+            ..Options::default()
+        };
+        let valid = serde_json::from_value::<Update>(json!({ "one": "1", "two": "2" })).unwrap();
+        let invalid = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+
+        let results = insert_or_update_many(
+            &pool,
+            &[
+                (InternalId(52), valid.clone()),
+                (InternalId(53), invalid.clone()),
+                (InternalId(54), valid.clone()),
+            ],
+            &options,
+            BatchMode::Continue,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(results[0], Ok(Outcome::Inserted { .. })));
+        assert!(matches!(results[1], Err(Error::Invalid(_))));
+        assert!(matches!(results[2], Ok(Outcome::Inserted { .. })));
+        assert_user!(fetch(&pool, InternalId(54)).await.unwrap(), { "one": "1" });
+        assert!(fetch_opt(&pool, InternalId(53)).await.unwrap().is_none());
+
+        let err = insert_or_update_many(
+            &pool,
+            &[(InternalId(55), valid), (InternalId(56), invalid)],
+            &options,
+            BatchMode::Abort,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::Invalid(_)));
+        assert!(fetch_opt(&pool, InternalId(55)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn background_applier() {
+        use applier::{Applier, ApplierConfig, ApplierReport};
+
+        let pool = db_connect().await;
+        let options = Arc::new(Options {
+            rules: vec![Rule::Requires("one", "two")],
+            ..Options::default()
+        });
+        let (applier, handle) = Applier::spawn(
+            pool.clone(),
+            options,
+            ApplierConfig {
+                window: Duration::from_secs(5),
+                max_batch: 4,
+                ..ApplierConfig::default()
+            },
+        )
+        .unwrap();
+
+        // 57 gets three patches that are combined into one, 58 breaks the rule
+        for (internal_id, payload) in [
+            (57, json!({ "one": "1", "two": "1" })),
+            (57, json!({ "two": "2" })),
+            (58, json!({ "one": "1" })),
+            (57, json!({ "one": "3", "two": "3" })),
+        ] {
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            applier
+                .send(InternalId(internal_id), payload)
+                .await
+                .unwrap();
+        }
+        drop(applier);
+
+        let report = handle.join().await.report;
+        assert_eq!(
+            report,
+            ApplierReport {
+                received: 4,
+                combined: 2,
+                coalesced: 0,
+                applied: 1,
+                failed: 1,
+                batches: 1,
+            }
+        );
+        assert_user!(fetch(&pool, InternalId(57)).await.unwrap(), { "one": "3", "two": "3" });
+        assert!(fetch_opt(&pool, InternalId(58)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn applier_limits_writes_per_key() {
+        use applier::{Applier, ApplierConfig, ApplierReport};
+
+        let pool = db_connect().await;
+        let (applier, handle) = Applier::spawn(
+            pool.clone(),
+            Arc::new(Options::default()),
+            ApplierConfig {
+                window: Duration::from_millis(10),
+                max_concurrent: 4,
+                min_key_interval: Some(Duration::from_millis(500)),
+                ..ApplierConfig::default()
+            },
+        )
+        .unwrap();
+
+        let send = |payload: Value| {
+            let applier = applier.clone();
+            async move {
+                let payload = serde_json::from_value::<Update>(payload).unwrap();
+                applier.send(InternalId(59), payload).await.unwrap();
+            }
+        };
+        send(json!({ "one": "1" })).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // written too recently, so these are held back and combined
+        send(json!({ "two": "2" })).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(json!({ "one": "3" })).await;
+        assert_user!(fetch(&pool, InternalId(59)).await.unwrap(), { "one": "1", "two": null });
+
+        drop(applier);
+        let report = handle.join().await.report;
+        assert_eq!(
+            report,
+            ApplierReport {
+                received: 3,
+                combined: 1,
+                coalesced: 0,
+                applied: 2,
+                failed: 0,
+                batches: 2,
+            }
+        );
+        assert_user!(fetch(&pool, InternalId(59)).await.unwrap(), { "one": "3", "two": "2" });
+    }
+
+    #[tokio::test]
+    async fn applier_coalesces_duplicates() {
+        use applier::{Applier, ApplierConfig, ApplierReport};
+
+        let pool = db_connect().await;
+        let (applier, handle) = Applier::spawn(
+            pool.clone(),
+            Arc::new(Options::default()),
+            ApplierConfig {
+                window: Duration::from_secs(5),
+                max_batch: 4,
+                ..ApplierConfig::default()
+            },
+        )
+        .unwrap();
+
+        // the last three change nothing the first one doesn't
+        for payload in [
+            json!({ "one": "1", "two": "2" }),
+            json!({ "one": "1", "two": "2" }),
+            json!({ "two": "2" }),
+            json!({ "one": "1", "two": "2" }),
+        ] {
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            applier.send(InternalId(61), payload).await.unwrap();
+        }
+        drop(applier);
+
+        let report = handle.join().await.report;
+        assert_eq!(
+            report,
+            ApplierReport {
+                received: 4,
+                combined: 0,
+                coalesced: 3,
+                applied: 1,
+                failed: 0,
+                batches: 1,
+            }
+        );
+        assert_user!(fetch(&pool, InternalId(61)).await.unwrap(), { "one": "1", "two": "2" });
+    }
+
+    #[test]
+    fn merge_patch() {
+        let update = Update::from_json(r#"{ "one": "1", "two": null, "profile": {} }"#).unwrap();
+        assert_eq!(update.to_merge_patch(), json!({ "one": "1", "two": null }));
+
+        let update = Update::from_json(r#"{ "profile": { "bio": null } }"#).unwrap();
+        assert_eq!(
+            update.to_merge_patch(),
+            json!({ "profile": { "bio": null } })
+        );
+    }
+
+    #[tokio::test]
+    async fn applier_shutdown() {
+        use applier::{Applier, ApplierConfig, Closed};
+
+        let pool = db_connect().await;
+        let (applier, handle) = Applier::spawn(
+            pool.clone(),
+            Arc::new(Options::default()),
+            ApplierConfig {
+                window: Duration::from_millis(10),
+                min_key_interval: Some(Duration::from_secs(60)),
+                ..ApplierConfig::default()
+            },
+        )
+        .unwrap();
+
+        let send = |payload: Value| {
+            let applier = applier.clone();
+            async move {
+                let payload = serde_json::from_value::<Update>(payload).unwrap();
+                applier.send(InternalId(65), payload).await
+            }
+        };
+        send(json!({ "one": "1" })).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // held back for a minute, which is past the deadline
+        send(json!({ "two": "2" })).await.unwrap();
+        send(json!({ "one": "3" })).await.unwrap();
+
+        let shutdown = handle.shutdown(Duration::from_millis(100)).await;
+        assert_eq!(shutdown.report.received, 3);
+        assert_eq!(shutdown.report.applied, 1);
+        assert_eq!(shutdown.unapplied.len(), 1);
+        let (internal_id, patch) = &shutdown.unapplied[0];
+        assert_eq!(*internal_id, InternalId(65));
+        assert_patch_eq!(
+            *patch,
+            serde_json::from_value::<Update>(json!({ "one": "3", "two": "2" })).unwrap()
+        );
+        assert_user!(fetch(&pool, InternalId(65)).await.unwrap(), { "one": "1", "two": null });
+
+        let err = send(json!({ "one": "4" })).await.unwrap_err();
+        assert!(matches!(err, Closed(InternalId(65), _)));
+    }
+
+    #[tokio::test]
+    async fn applier_returns_failed_batches() {
+        use applier::{Applier, ApplierConfig};
+        use std::time::Duration;
+
+        let pool = db_connect().await;
+        // the batch fails as a whole since the role can't be set
+        let options = Options {
+            role: Some("no_such_role".to_owned()),
+            ..Options::default()
+        };
+        let (applier, handle) = Applier::spawn(
+            pool.clone(),
+            Arc::new(options),
+            ApplierConfig {
+                window: Duration::from_millis(10),
+                ..ApplierConfig::default()
+            },
+        )
+        .unwrap();
+
+        for (internal_id, payload) in [(68, json!({ "one": "1" })), (69, json!({ "two": "2" }))] {
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            applier
+                .send(InternalId(internal_id), payload)
+                .await
+                .unwrap();
+        }
+        drop(applier);
+
+        let shutdown = handle.join().await;
+        assert_eq!(shutdown.report.failed, 2);
+        let keys = shutdown
+            .unapplied
+            .iter()
+            .map(|(internal_id, _)| internal_id.0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![68, 69]);
+        assert!(fetch_opt(&pool, InternalId(68)).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn locale_is_trimmed() {
+        let update = Update::from_json(r#"{ "locale": " da " }"#).unwrap();
+        assert_eq!(update.locale, Some(Some("da".to_owned())));
+    }
+
+    #[test]
+    fn empty_bio_is_null() {
+        let update = Update::from_json(r#"{ "profile": { "bio": "" } }"#).unwrap();
+        assert_eq!(update.profile.unwrap().bio, Some(None));
+    }
+
+    #[test]
+    fn errors_include_path() {
+        let err = Update::from_json(r#"{ "one": "1", "profile": { "bio": 1 } }"#).unwrap_err();
+        assert_eq!(err.path().to_string(), "profile.bio");
+        assert!(err
+            .to_string()
+            .starts_with("profile.bio: invalid type: integer `1`, expected a string"));
+
+        let update = Update::from_json(r#"{ "one": null }"#).unwrap();
+        assert_eq!(update.one, Some(None));
+    }
+
+    #[test]
+    fn field_lists_match_fields() {
+        let erasable = erase::ERASABLE.iter().map(|(field, _, _)| *field);
+        for field in redact::SENSITIVE
+            .iter()
+            .chain(normalize::TEXT_FIELDS)
+            .copied()
+            .chain(erasable)
+        {
+            assert!(FIELDS.contains(&field), "`{}` isn't in `FIELDS`", field);
+        }
+
+        // `metadata` holds any JSON, strings included, but isn't text
+        for field in FIELDS.iter().filter(|field| **field != "metadata") {
+            let patch = match field.strip_prefix("profile.") {
+                Some(field) => json!({ "profile": { field: "x" } }),
+                None => json!({ *field: "x" }),
+            };
+            let is_text = serde_json::from_value::<Update>(patch).is_ok();
+            assert_eq!(
+                normalize::TEXT_FIELDS.contains(field),
+                is_text,
+                "`{}` in `TEXT_FIELDS`",
+                field
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn enum_fields() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(9);
+
+        let payload = serde_json::from_value::<Update>(json!({ "status": "active" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.status, Some(UserStatus::Active));
+
+        let payload = serde_json::from_value::<Update>(json!({ "status": "suspended" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.status, Some(UserStatus::Suspended));
+
+        let payload = serde_json::from_value::<Update>(json!({ "status": null })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.status, None);
+
+        assert!(serde_json::from_value::<Update>(json!({ "status": "unknown" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn insert_defaults() {
+        let pool = db_connect().await;
+
+        // missing fields get their insert default
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload
+            .insert_or_update(InternalId(10), &pool)
+            .await
+            .unwrap();
+
+        let user = fetch(&pool, InternalId(10)).await.unwrap();
+        assert_eq!(user.status, Some(UserStatus::Active));
+
+        // but updates still leave them untouched
+        let con = pool.get().await.unwrap();
+        con.execute("update users set status = null where internal_id = 10", &[])
+            .await
+            .unwrap();
+        drop(con);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload
+            .insert_or_update(InternalId(10), &pool)
+            .await
+            .unwrap();
+
+        let user = fetch(&pool, InternalId(10)).await.unwrap();
+        assert_eq!(user.status, None);
+
+        // explicit null is inserted as null
+        let payload = serde_json::from_value::<Update>(json!({ "status": null })).unwrap();
+        payload
+            .insert_or_update(InternalId(11), &pool)
+            .await
+            .unwrap();
+
+        let user = fetch(&pool, InternalId(11)).await.unwrap();
+        assert_eq!(user.status, None);
+    }
+
+    #[tokio::test]
+    async fn null_means_default() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(12);
+
+        let payload = serde_json::from_value::<Update>(json!({ "locale": null })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.locale, "en");
+
+        let payload = serde_json::from_value::<Update>(json!({ "locale": "da" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.locale, "da");
+
+        let payload = serde_json::from_value::<Update>(json!({ "locale": null })).unwrap();
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["locale"]
+            }
+        );
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.locale, "en");
+    }
+
+    #[tokio::test]
+    async fn update_where() {
+        let pool = db_connect().await;
+
+        for (internal_id, organization_id) in [(15, 1), (16, 1), (17, 2)] {
+            let payload = json!({ "one": "1", "two": "1", "organization_id": organization_id });
+            let payload = serde_json::from_value::<Update>(payload).unwrap();
+            payload
+                .insert_or_update(InternalId(internal_id), &pool)
+                .await
+                .unwrap();
+        }
+
+        let payload = json!({ "one": "2", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        let updated = payload
+            .update_where(&UserFilter::OrganizationId(1), &pool, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(updated, 2);
+
+        let users = fetch_many_by_key(
+            &pool,
+            &[InternalId(15), InternalId(16), InternalId(17)],
+            &Options::default(),
+        )
+        .await
+        .unwrap();
+        for internal_id in [15, 16] {
+            assert_eq!(users[&InternalId(internal_id)].one.as_deref(), Some("2"));
+            assert_eq!(users[&InternalId(internal_id)].two.as_deref(), Some("1"));
+            assert_eq!(users[&InternalId(internal_id)].bio.as_deref(), Some("hi"));
+        }
+        assert_eq!(users[&InternalId(17)].one.as_deref(), Some("1"));
+        assert_eq!(users[&InternalId(17)].bio, None);
+
+        // profile only patches touch the users too
+        let con = pool.get().await.unwrap();
+        let before = con
+            .query_one(
+                "select max(updated_at) as updated_at from users where organization_id = 1",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get::<_, SystemTime>("updated_at");
+        let payload =
+            serde_json::from_value::<Update>(json!({ "profile": { "bio": "hey" } })).unwrap();
+        let updated = payload
+            .update_where(&UserFilter::OrganizationId(1), &pool, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(updated, 2);
+        let after = con
+            .query_one(
+                "select min(updated_at) as updated_at from users where organization_id = 1",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get::<_, SystemTime>("updated_at");
+        assert!(after > before);
+
+        // the patch is checked like a single patch
+        let payload = serde_json::from_value::<Update>(json!({ "two": "2" })).unwrap();
+        let options = Options {
+            policy: Some(Arc::new(access::FieldAccess::default().write(&["one"]))),
+            ..Options::default()
+        };
+        let err = payload
+            .update_where(&UserFilter::OrganizationId(1), &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Forbidden(fields) if fields == vec!["two"]));
+        let options = Options {
+            rules: vec![Rule::Requires("two", "one")],
+            ..Options::default()
+        };
+        let err = payload
+            .update_where(&UserFilter::OrganizationId(1), &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Invalid(_)));
+
+        // validators run for every matching user, here 15 sees that 16 has the same value
+        let payload = serde_json::from_value::<Update>(json!({ "one": "taken by 16" })).unwrap();
+        payload
+            .clone()
+            .insert_or_update(InternalId(16), &pool)
+            .await
+            .unwrap();
+        let options = Options {
+            validators: vec![Arc::new(UniqueOne)],
+            ..Options::default()
+        };
+        let err = payload
+            .update_where(&UserFilter::OrganizationId(1), &pool, &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid patch: `one` is already taken");
+
+        let payload = serde_json::from_value::<Update>(json!({ "two": " Padded " })).unwrap();
+        let cache = Arc::new(Invalidated::default());
+        let options = Options {
+            normalize: vec![(
+                "two",
+                normalize::Pipeline::new().then(normalize::Step::Trim),
+            )],
+            cache: Some(cache.clone()),
+            ..Options::default()
+        };
+        payload
+            .update_where(&UserFilter::OrganizationId(1), &pool, &options)
+            .await
+            .unwrap();
+        let user = fetch(&pool, InternalId(15)).await.unwrap();
+        assert_eq!(user.two.as_deref(), Some("Padded"));
+        assert_eq!(
+            *cache.0.lock().unwrap(),
+            vec![InternalId(15), InternalId(16)]
+        );
+    }
+
+    #[tokio::test]
+    async fn list_users() {
+        let pool = db_connect().await;
+
+        for internal_id in 18..=22 {
+            let payload =
+                serde_json::from_value::<Update>(json!({ "organization_id": 3 })).unwrap();
+            payload
+                .insert_or_update(InternalId(internal_id), &pool)
+                .await
+                .unwrap();
+        }
+
+        let filter = UserFilter::OrganizationId(3);
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = list(&pool, after, 2, Some(&filter), &Options::default())
+                .await
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().map(|user| user.internal_id);
+            pages.push(
+                page.iter()
+                    .map(|user| user.internal_id.0)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        assert_eq!(pages, vec![vec![18, 19], vec![20, 21], vec![22]]);
+    }
+
+    #[tokio::test]
+    async fn expected_values() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(23);
+
+        let payload = json!({ "one": "1", "two": "1", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        // someone else changes `two`, but we only write `one`
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let options = Options {
+            expected: Some(serde_json::from_value(json!({ "one": "1", "two": "0" })).unwrap()),
+            ..Options::default()
+        };
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        // but writing a field that changed since we saw it fails
+        let payload = json!({ "one": "3", "profile": { "bio": "bye" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        let options = Options {
+            expected: Some(
+                serde_json::from_value(json!({ "one": "1", "profile": { "bio": null } })).unwrap(),
+            ),
+            ..Options::default()
+        };
+        let err = payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        match err {
+            Error::Stale(fields) => assert_eq!(
+                fields,
+                vec![
+                    StaleField {
+                        field: "one",
+                        expected: json!("1"),
+                        current: json!("2"),
+                    },
+                    StaleField {
+                        field: "profile.bio",
+                        expected: json!(null),
+                        current: json!("hi"),
+                    },
+                ],
+            ),
+            other => panic!("unexpected error: {}", other),
+        }
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.bio.as_deref(), Some("hi"));
+    }
+
+    #[tokio::test]
+    async fn conflict_policies() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(25);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1", "two": "1" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        // we last saw `one` and `two` as "0" but both have changed since
+        let expected = json!({ "one": "0", "two": "0" });
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2", "two": "2" })).unwrap();
+        let options = Options {
+            expected: Some(serde_json::from_value(expected.clone()).unwrap()),
+            conflict_policy: ConflictPolicy::LastWriteWins,
+            ..Options::default()
+        };
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.two.as_deref(), Some("2"));
+
+        // `one` is still stale but `two` is what we saw
+        let expected = json!({ "one": "0", "two": "2" });
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "3", "two": "3" })).unwrap();
+        let options = Options {
+            expected: Some(serde_json::from_value(expected).unwrap()),
+            conflict_policy: ConflictPolicy::Merge,
+            log: Some(PatchMeta::default()),
+            ..Options::default()
+        };
+        let outcome = payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["two"]
+            }
+        );
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.two.as_deref(), Some("3"));
+
+        // only what was applied is logged
+        let con = pool.get().await.unwrap();
+        let row = con
+            .query_one(
+                "select patch from patches where internal_id = $1",
+                &[&internal_id],
+            )
+            .await
+            .unwrap();
+        let patch: Json<Value> = row.get("patch");
+        assert_eq!(patch.0, json!({ "two": "3" }));
+    }
+
+    #[tokio::test]
+    async fn if_unmodified_since() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(43);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        let options = Options {
+            require_precondition: true,
+            ..Options::default()
+        };
+        let err = payload
+            .clone()
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::PreconditionRequired));
+
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let now = std::time::SystemTime::now();
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let options = Options {
+            if_unmodified_since: Some(now),
+            require_precondition: true,
+            ..Options::default()
+        };
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "3" })).unwrap();
+        let options = Options {
+            if_unmodified_since: Some(now - std::time::Duration::from_secs(60)),
+            ..Options::default()
+        };
+        let err = payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        let current = fetch(&pool, internal_id).await.unwrap();
+        match err {
+            Error::PreconditionFailed(Some(etag)) => assert_eq!(etag, current.etag()),
+            other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(current.one.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn if_match() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(24);
+
+        // `If-Match` never matches a row that doesn't exist
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        let options = Options {
+            if_match: Some("*".to_owned()),
+            ..Options::default()
+        };
+        let err = payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::PreconditionFailed(None)));
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+        let etag = fetch(&pool, internal_id).await.unwrap().etag();
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let options = Options {
+            if_match: Some(etag.clone()),
+            ..Options::default()
+        };
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        // the old ETag is stale now
+        let payload = serde_json::from_value::<Update>(json!({ "one": "3" })).unwrap();
+        let err = payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap_err();
+        let current = fetch(&pool, internal_id).await.unwrap().etag();
+        assert_ne!(current, etag);
+        match err {
+            Error::PreconditionFailed(Some(etag)) => assert_eq!(etag, current),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn preconditions_whatever_conflict_policy() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(71);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+        let stale = fetch(&pool, internal_id).await.unwrap().etag();
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        for conflict_policy in [
+            ConflictPolicy::Reject,
+            ConflictPolicy::LastWriteWins,
+            ConflictPolicy::Merge,
+        ] {
+            let payload = serde_json::from_value::<Update>(json!({ "one": "3" })).unwrap();
+            let options = Options {
+                if_match: Some(stale.clone()),
+                conflict_policy,
+                ..Options::default()
+            };
+            let err = payload
+                .insert_or_update_with_options(internal_id, &pool, &options)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, Error::PreconditionFailed(Some(_))),
+                "{:?}: {}",
+                conflict_policy,
+                err
+            );
+
+            let payload = serde_json::from_value::<Update>(json!({ "one": "3" })).unwrap();
+            let options = Options {
+                if_unmodified_since: Some(
+                    std::time::SystemTime::now() - std::time::Duration::from_secs(60),
+                ),
+                conflict_policy,
+                ..Options::default()
+            };
+            let err = payload
+                .insert_or_update_with_options(internal_id, &pool, &options)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, Error::PreconditionFailed(Some(_))),
+                "{:?}: {}",
+                conflict_policy,
+                err
+            );
+        }
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn history() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(26);
+        let options = Options {
+            history: true,
+            ..Options::default()
+        };
+
+        let payload = json!({ "one": "1", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        let first = std::time::SystemTime::now();
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+        let second = std::time::SystemTime::now();
+
+        // noops don't add a version
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        let user = history::fetch_as_of(&pool, internal_id, first, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("1"));
+        assert_eq!(user.bio.as_deref(), Some("hi"));
+
+        let user = history::fetch_as_of(&pool, internal_id, second, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+
+        let before = first - std::time::Duration::from_secs(60);
+        let err = history::fetch_as_of(&pool, internal_id, before, &Options::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+
+        let con = pool.get().await.unwrap();
+        let versions: i64 = con
+            .query_one(
+                "select count(*) from users_history where internal_id = $1",
+                &[&internal_id],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(versions, 2);
+    }
+
+    #[tokio::test]
+    async fn soft_deleted() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(7);
+
+        let payload = serde_json::from_value::<Update>(json!({ "one": "1" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let con = pool.get().await.unwrap();
+        con.execute(
+            "update users set deleted_at = now() where internal_id = $1",
+            &[&internal_id],
+        )
+        .await
+        .unwrap();
+        drop(con);
+
+        // fails by default
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let err = payload
+            .insert_or_update(internal_id, &pool)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Deleted));
+
+        // skipping leaves the row untouched
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let options = Options {
+            deleted: DeletedPolicy::Skip,
+            ..Options::default()
+        };
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        let user = fetch_including_deleted(&pool, internal_id, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(user.one.as_deref(), Some("1"));
+        assert!(user.deleted_at.is_some());
+
+        // resurrecting applies the patch and undeletes the row
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        let options = Options {
+            deleted: DeletedPolicy::Resurrect,
+            ..Options::default()
+        };
+        payload
+            .insert_or_update_with_options(internal_id, &pool, &options)
+            .await
+            .unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert!(user.deleted_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn profile() {
+        let pool = db_connect().await;
+
+        let internal_id = InternalId(8);
+
+        let payload = json!({ "one": "1", "profile": { "bio": "hi" } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("1"));
+        assert_eq!(user.bio.as_deref(), Some("hi"));
+
+        // missing profile leaves it untouched
+        let payload = serde_json::from_value::<Update>(json!({ "one": "2" })).unwrap();
+        payload.insert_or_update(internal_id, &pool).await.unwrap();
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.bio.as_deref(), Some("hi"));
+
+        let payload = json!({ "profile": { "bio": null } });
+        let payload = serde_json::from_value::<Update>(payload).unwrap();
+        let outcome = payload.insert_or_update(internal_id, &pool).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome::Updated {
+                changed_fields: vec!["profile.bio"]
+            }
+        );
+
+        let user = fetch(&pool, internal_id).await.unwrap();
+        assert_eq!(user.one.as_deref(), Some("2"));
+        assert_eq!(user.bio, None);
+    }
+
+    #[tokio::test]
+    async fn fetch_many_users() {
+        let pool = db_connect().await;
+
+        for (internal_id, one) in [(13, "a"), (14, "b")] {
+            let payload = serde_json::from_value::<Update>(json!({ "one": one })).unwrap();
+            payload
+                .insert_or_update(InternalId(internal_id), &pool)
+                .await
+                .unwrap();
+        }
+
+        let users = fetch_many(
+            &pool,
+            &[InternalId(14), InternalId(13), InternalId(404)],
+            &Options::default(),
+        )
+        .await
+        .unwrap();
+        let ids = users
+            .iter()
+            .map(|user| user.internal_id.0)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![13, 14]);
+
+        let users = fetch_many_by_key(
+            &pool,
+            &[InternalId(13), InternalId(14)],
+            &Options::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(users[&InternalId(13)].one.as_deref(), Some("a"));
+        assert_eq!(users[&InternalId(14)].one.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn health() {
+        let pool = db_connect().await;
+        health_check(&pool, Duration::from_secs(5)).await.unwrap();
+
+        // nothing is listening on this port
+        let pool = PoolConfig::from_url("host=localhost port=1 user=david.pedersen dbname=testing")
+            .unwrap()
+            .connection_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+        assert!(health_check(&pool, Duration::from_secs(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pool_tls() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio_postgres::{tls::MakeTlsConnect, NoTls, Socket};
+
+        /// Connects like `NoTls`, counting the connections it was asked to secure.
+        #[derive(Clone, Default)]
+        struct Counting(Arc<AtomicUsize>);
+
+        impl MakeTlsConnect<Socket> for Counting {
+            type Stream = <NoTls as MakeTlsConnect<Socket>>::Stream;
+            type TlsConnect = NoTls;
+            type Error = <NoTls as MakeTlsConnect<Socket>>::Error;
+
+            fn make_tls_connect(&mut self, domain: &str) -> Result<NoTls, Self::Error> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                MakeTlsConnect::<Socket>::make_tls_connect(&mut NoTls, domain)
+            }
+        }
+
+        // make sure the database exists
+        db_connect().await;
+
+        // the test server doesn't do TLS, so `prefer` falls back to plain text
+        let tls = Counting::default();
+        let pool = PoolConfig::from_url("host=localhost user=david.pedersen dbname=testing")
+            .unwrap()
+            .tls(tls.clone())
+            .build()
+            .await
+            .unwrap();
+        health_check(&pool, Duration::from_secs(5)).await.unwrap();
+        assert!(tls.0.load(Ordering::SeqCst) > 0);
+
+        for tls in [Some(Counting::default()), None] {
+            let config = PoolConfig::from_url(
+                "host=localhost user=david.pedersen dbname=testing sslmode=require",
+            )
+            .unwrap()
+            .connection_timeout(Duration::from_millis(500));
+            let config = match tls {
+                Some(tls) => config.tls(tls),
+                None => config,
+            };
+            let pool = config.build().await.unwrap();
+            assert!(health_check(&pool, Duration::from_secs(1)).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_session_settings() {
+        // make sure the database exists
+        db_connect().await;
+
+        let pool = PoolConfig::from_url("host=localhost user=david.pedersen dbname=testing")
+            .unwrap()
+            .application_name("upsert-sql tests")
+            .search_path(&["pg_catalog", "public"])
+            .setting("app.note", r"two words and a \")
+            .build()
+            .await
+            .unwrap();
+        let con = pool.get().await.unwrap();
+        let row = con
+            .query_one(
+                r#"
+                select
+                    current_setting('application_name'),
+                    current_setting('search_path'),
+                    current_setting('app.note')
+                "#,
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), "upsert-sql tests");
+        assert_eq!(row.get::<_, String>(1), "pg_catalog, public");
+        assert_eq!(row.get::<_, String>(2), r"two words and a \");
+    }
+
+    #[tokio::test]
+    async fn fetch_missing() {
+        let pool = db_connect().await;
+
+        let err = fetch(&pool, InternalId(404)).await.unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+
+        assert!(fetch_opt(&pool, InternalId(404)).await.unwrap().is_none());
+    }
+
+    async fn db_connect() -> DbPool {
+        // tests run in parallel so only recreate the database once
+        static SETUP: Once = Once::new();
+        SETUP.call_once(|| {
+            assert!(Command::new("./setup").status().unwrap().success());
+        });
+
+        PoolConfig::from_url("host=localhost user=david.pedersen dbname=testing")
+            .unwrap()
             .max_size(32)
-            .build(manager)
+            .build()
             .await
             .unwrap()
     }
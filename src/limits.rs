@@ -0,0 +1,144 @@
+use crate::Update;
+use std::fmt;
+
+/// Limits checked before a patch is deserialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchLimits {
+    /// Maximum size of the body in bytes. Defaults to 64 KiB.
+    pub max_bytes: usize,
+    /// Maximum nesting of objects and arrays. Defaults to 16.
+    pub max_depth: usize,
+}
+
+impl Default for PatchLimits {
+    fn default() -> Self {
+        PatchLimits {
+            max_bytes: 64 * 1024,
+            max_depth: 16,
+        }
+    }
+}
+
+/// Why a patch couldn't be parsed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The body is larger than `PatchLimits::max_bytes`.
+    TooLarge,
+    /// The body nests deeper than `PatchLimits::max_depth`.
+    TooDeep,
+    Invalid(serde_path_to_error::Error<serde_json::Error>),
+    /// The payload's version isn't one `PayloadVersions` can upgrade.
+    UnsupportedVersion(serde_json::Value),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooLarge => write!(f, "patch is too large"),
+            ParseError::TooDeep => write!(f, "patch is nested too deeply"),
+            ParseError::Invalid(err) => write!(f, "{}", err),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported payload version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Invalid(err) => Some(err),
+            ParseError::TooLarge | ParseError::TooDeep | ParseError::UnsupportedVersion(_) => None,
+        }
+    }
+}
+
+impl PatchLimits {
+    /// Fail if `json` exceeds the limits, without deserializing it.
+    pub(crate) fn check(&self, json: &str) -> Result<(), ParseError> {
+        if json.len() > self.max_bytes {
+            return Err(ParseError::TooLarge);
+        }
+        if depth(json) > self.max_depth {
+            return Err(ParseError::TooDeep);
+        }
+        Ok(())
+    }
+}
+
+impl Update {
+    /// Like `Update::from_json` but rejects bodies exceeding `limits` without deserializing them.
+    pub(crate) fn from_json_with_limits(
+        json: &str,
+        limits: &PatchLimits,
+    ) -> Result<Self, ParseError> {
+        limits.check(json)?;
+        Update::from_json(json).map_err(ParseError::Invalid)
+    }
+}
+
+/// Deepest nesting of objects and arrays, ignoring brackets in strings. Doesn't validate the JSON,
+/// that is left to serde.
+pub(crate) fn depth(json: &str) -> usize {
+    let mut depth = 0_usize;
+    let mut max = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nesting_depth() {
+        assert_eq!(depth(r#"{}"#), 1);
+        assert_eq!(depth(r#"{ "a": [1, { "b": [] }] }"#), 4);
+        assert_eq!(depth(r#"{ "a": "[[[{{{\"" }"#), 1);
+    }
+
+    #[test]
+    fn limits() {
+        let limits = PatchLimits {
+            max_bytes: 40,
+            max_depth: 3,
+        };
+
+        let patch = Update::from_json_with_limits(r#"{ "metadata": { "a": [1] } }"#, &limits);
+        assert!(patch.is_ok());
+
+        let patch = Update::from_json_with_limits(r#"{ "metadata": { "a": [[1]] } }"#, &limits);
+        assert!(matches!(patch, Err(ParseError::TooDeep)));
+
+        let patch = Update::from_json_with_limits(
+            &format!(r#"{{ "one": "{}" }}"#, "a".repeat(40)),
+            &limits,
+        );
+        assert!(matches!(patch, Err(ParseError::TooLarge)));
+
+        let patch = Update::from_json_with_limits(r#"{ "one": 1 }"#, &limits);
+        assert!(matches!(patch, Err(ParseError::Invalid(_))));
+    }
+}
@@ -0,0 +1,189 @@
+//! Random patches for load testing the update path.
+//!
+//! Aim `drive` at a small range of keys to see how the row locks taken by `insert_or_update`
+//! hold up under contention.
+
+use crate::{DbPool, InternalId, Outcome, ProfileUpdate, Update, UserStatus};
+use rand::{seq::SliceRandom, Rng};
+use serde_json::json;
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const WORDS: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel",
+];
+const LOCALES: &[&str] = &["en", "en-GB", "da", "de", "fr"];
+
+/// Generates random patches.
+#[derive(Debug, Clone)]
+pub(crate) struct PatchGenerator {
+    /// Probability that a field is in the patch. Defaults to 0.5.
+    pub(crate) present: f64,
+    /// Probability that a field in the patch is `null`. Defaults to 0.1.
+    pub(crate) null: f64,
+    /// Organizations to move users between. Defaults to `1..4`.
+    pub(crate) organizations: Range<i64>,
+}
+
+impl Default for PatchGenerator {
+    fn default() -> Self {
+        PatchGenerator {
+            present: 0.5,
+            null: 0.1,
+            organizations: 1..4,
+        }
+    }
+}
+
+impl PatchGenerator {
+    pub(crate) fn generate<R: Rng>(&self, rng: &mut R) -> Update {
+        let bio = self.field(rng, |rng| format!("likes {}", word(rng)));
+        Update {
+            one: self.field(rng, |rng| word(rng).to_owned()),
+            two: self.field(rng, |rng| word(rng).to_owned()),
+            metadata: self.field(rng, |rng| json!({ "n": rng.gen_range(0..100) })),
+            status: self.field(rng, |rng| {
+                *[UserStatus::Active, UserStatus::Suspended]
+                    .choose(rng)
+                    .unwrap()
+            }),
+            locale: self.field(rng, |rng| LOCALES.choose(rng).unwrap().to_string()),
+            organization_id: self.field(rng, |rng| rng.gen_range(self.organizations.clone())),
+            profile: bio.map(|bio| ProfileUpdate { bio: Some(bio) }),
+        }
+    }
+
+    fn field<R, T, F>(&self, rng: &mut R, value: F) -> Option<Option<T>>
+    where
+        R: Rng,
+        F: FnOnce(&mut R) -> T,
+    {
+        if !rng.gen_bool(self.present) {
+            None
+        } else if rng.gen_bool(self.null) {
+            Some(None)
+        } else {
+            Some(Some(value(rng)))
+        }
+    }
+}
+
+fn word<R: Rng>(rng: &mut R) -> &'static str {
+    WORDS.choose(rng).unwrap()
+}
+
+/// What happened to the patches sent by `drive`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LoadReport {
+    pub(crate) inserted: usize,
+    pub(crate) updated: usize,
+    pub(crate) noop: usize,
+    pub(crate) failed: usize,
+    pub(crate) elapsed: Duration,
+}
+
+/// Apply `patches` random patches to keys picked from `keys`, with `concurrency` patches in
+/// flight at a time.
+pub(crate) async fn drive(
+    pool: &DbPool,
+    generator: PatchGenerator,
+    keys: Range<i64>,
+    patches: usize,
+    concurrency: usize,
+) -> LoadReport {
+    let started = Instant::now();
+    let sent = Arc::new(AtomicUsize::new(0));
+
+    let workers = (0..concurrency.max(1))
+        .map(|_| {
+            let pool = pool.clone();
+            let keys = keys.clone();
+            let sent = Arc::clone(&sent);
+            let generator = generator.clone();
+            tokio::spawn(async move {
+                let mut report = LoadReport::default();
+                while sent.fetch_add(1, Ordering::Relaxed) < patches {
+                    // `ThreadRng` isn't `Send` so it must not be held across the await
+                    let (internal_id, patch) = {
+                        let mut rng = rand::thread_rng();
+                        (
+                            InternalId(rng.gen_range(keys.clone())),
+                            generator.generate(&mut rng),
+                        )
+                    };
+                    match patch.insert_or_update(internal_id, &pool).await {
+                        Ok(Outcome::Inserted { .. }) => report.inserted += 1,
+                        Ok(Outcome::Updated { .. }) => report.updated += 1,
+                        Ok(Outcome::Noop) => report.noop += 1,
+                        Err(_) => report.failed += 1,
+                    }
+                }
+                report
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut report = LoadReport::default();
+    for worker in workers {
+        // a worker only fails to join if it panicked
+        let worker = worker.await.unwrap_or_default();
+        report.inserted += worker.inserted;
+        report.updated += worker.updated;
+        report.noop += worker.noop;
+        report.failed += worker.failed;
+    }
+    report.elapsed = started.elapsed();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probabilities() {
+        let mut rng = rand::thread_rng();
+
+        let patch = PatchGenerator {
+            present: 0.0,
+            null: 0.0,
+            ..PatchGenerator::default()
+        }
+        .generate(&mut rng);
+        assert_eq!(serde_json::to_value(&patch).unwrap(), json!({}));
+
+        let patch = PatchGenerator {
+            present: 1.0,
+            null: 1.0,
+            ..PatchGenerator::default()
+        }
+        .generate(&mut rng);
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            json!({
+                "one": null,
+                "two": null,
+                "metadata": null,
+                "status": null,
+                "locale": null,
+                "organization_id": null,
+                "profile": { "bio": null },
+            })
+        );
+
+        let patch = PatchGenerator {
+            present: 1.0,
+            null: 0.0,
+            ..PatchGenerator::default()
+        }
+        .generate(&mut rng);
+        assert!(WORDS.contains(&patch.one.unwrap().unwrap().as_str()));
+        assert!(patch.profile.unwrap().bio.unwrap().is_some());
+    }
+}
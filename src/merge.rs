@@ -0,0 +1,230 @@
+use crate::{ProfileUpdate, Update, User};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+/// Two patches set the same field to different values.
+#[derive(Clone, PartialEq)]
+pub struct Conflict {
+    pub field: &'static str,
+    pub base: Value,
+    pub ours: Value,
+    pub theirs: Value,
+}
+
+/// A field whose current value isn't the value the client last saw.
+#[derive(Clone, PartialEq)]
+pub struct StaleField {
+    pub field: &'static str,
+    pub expected: Value,
+    pub current: Value,
+}
+
+/// Every field that couldn't be merged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflicts(pub Vec<Conflict>);
+
+impl fmt::Display for Conflicts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = self
+            .0
+            .iter()
+            .map(|conflict| conflict.field)
+            .collect::<Vec<_>>();
+        write!(f, "conflicting changes to {}", fields.join(", "))
+    }
+}
+
+impl std::error::Error for Conflicts {}
+
+impl Update {
+    /// Merge two patches that were both made against `base`.
+    ///
+    /// A field changed by only one side is taken from that side. A field set to the same value by
+    /// both sides, or left at its `base` value by one of them, isn't a conflict.
+    pub(crate) fn merge3(base: &User, ours: Update, theirs: Update) -> Result<Update, Conflicts> {
+        let mut conflicts = Vec::new();
+
+        let profile = match (ours.profile, theirs.profile) {
+            (None, profile) | (profile, None) => profile,
+            (Some(ours), Some(theirs)) => Some(ProfileUpdate {
+                bio: merge_field(
+                    "profile.bio",
+                    &base.bio,
+                    ours.bio,
+                    theirs.bio,
+                    &mut conflicts,
+                ),
+            }),
+        };
+
+        let merged = Update {
+            one: merge_field("one", &base.one, ours.one, theirs.one, &mut conflicts),
+            two: merge_field("two", &base.two, ours.two, theirs.two, &mut conflicts),
+            metadata: merge_field(
+                "metadata",
+                &base.metadata,
+                ours.metadata,
+                theirs.metadata,
+                &mut conflicts,
+            ),
+            status: merge_field(
+                "status",
+                &base.status,
+                ours.status,
+                theirs.status,
+                &mut conflicts,
+            ),
+            locale: merge_field(
+                "locale",
+                &Some(base.locale.clone()),
+                ours.locale,
+                theirs.locale,
+                &mut conflicts,
+            ),
+            organization_id: merge_field(
+                "organization_id",
+                &base.organization_id,
+                ours.organization_id,
+                theirs.organization_id,
+                &mut conflicts,
+            ),
+            profile,
+        };
+
+        if conflicts.is_empty() {
+            Ok(merged)
+        } else {
+            Err(Conflicts(conflicts))
+        }
+    }
+
+    /// Combine with a patch applied after this one, so applying the result is the same as
+    /// applying both in order. Fields present in `later` win.
+    pub(crate) fn then(self, later: Update) -> Update {
+        let profile = match (self.profile, later.profile) {
+            (profile, None) | (None, profile) => profile,
+            (Some(earlier), Some(later)) => Some(ProfileUpdate {
+                bio: later.bio.or(earlier.bio),
+            }),
+        };
+
+        Update {
+            one: later.one.or(self.one),
+            two: later.two.or(self.two),
+            metadata: later.metadata.or(self.metadata),
+            status: later.status.or(self.status),
+            locale: later.locale.or(self.locale),
+            organization_id: later.organization_id.or(self.organization_id),
+            profile,
+        }
+    }
+}
+
+fn merge_field<T>(
+    field: &'static str,
+    base: &Option<T>,
+    ours: Option<Option<T>>,
+    theirs: Option<Option<T>>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<Option<T>>
+where
+    T: PartialEq + Serialize,
+{
+    match (ours, theirs) {
+        (None, patch) | (patch, None) => patch,
+        (Some(ours), Some(theirs)) if ours == theirs || theirs == *base => Some(ours),
+        (Some(ours), Some(theirs)) if ours == *base => Some(theirs),
+        (Some(ours), Some(theirs)) => {
+            conflicts.push(Conflict {
+                field,
+                base: to_value(base),
+                ours: to_value(&ours),
+                theirs: to_value(&theirs),
+            });
+            Some(ours)
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::assert_patch_eq, InternalId};
+    use serde_json::json;
+
+    fn user() -> User {
+        User {
+            id: 1,
+            internal_id: InternalId(1),
+            one: Some("base".to_owned()),
+            two: Some("base".to_owned()),
+            metadata: None,
+            deleted_at: None,
+            status: None,
+            locale: "en".to_owned(),
+            organization_id: None,
+            bio: None,
+        }
+    }
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn merges_independent_changes() {
+        let merged = Update::merge3(
+            &user(),
+            update(json!({ "one": "ours", "two": "base" })),
+            update(json!({ "two": "theirs", "profile": { "bio": "hi" } })),
+        )
+        .unwrap();
+
+        assert_patch_eq!(
+            merged,
+            update(json!({ "one": "ours", "two": "theirs", "profile": { "bio": "hi" } })),
+        );
+    }
+
+    #[test]
+    fn then() {
+        let combined = update(json!({ "one": "1", "two": "1", "profile": { "bio": "1" } })).then(
+            update(json!({ "two": null, "locale": "da", "profile": {} })),
+        );
+        assert_patch_eq!(
+            combined,
+            update(json!({
+                "one": "1",
+                "two": null,
+                "locale": "da",
+                "profile": { "bio": "1" },
+            })),
+        );
+    }
+
+    #[test]
+    fn reports_conflicts() {
+        let conflicts = Update::merge3(
+            &user(),
+            update(json!({ "one": "ours", "two": null })),
+            update(json!({ "one": "theirs", "two": null })),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            conflicts,
+            Conflicts(vec![Conflict {
+                field: "one",
+                base: json!("base"),
+                ours: json!("ours"),
+                theirs: json!("theirs"),
+            }])
+        );
+        assert_eq!(conflicts.to_string(), "conflicting changes to one");
+    }
+}
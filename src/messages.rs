@@ -0,0 +1,109 @@
+//! Rendering per-field problems for end users.
+//!
+//! The `Display` impls of `Violation` and `ParseError` are English and meant for logs. Implement
+//! `Messages` to show them to users in their own language, for example by looking the message up
+//! in a fluent bundle.
+
+use crate::{ParseError, Rule, Violation};
+
+/// Renders problems with a patch as messages for one locale.
+///
+/// Every method defaults to the English `Display` output so implementations can translate only
+/// some messages.
+pub trait Messages {
+    fn violation(&self, violation: &Violation) -> String {
+        violation.to_string()
+    }
+
+    /// `path` is where in the patch the bad value is, such as `profile.bio`.
+    fn invalid_value(&self, path: &str, error: &serde_json::Error) -> String {
+        format!("{}: {}", path, error)
+    }
+
+    fn too_large(&self) -> String {
+        ParseError::TooLarge.to_string()
+    }
+
+    fn too_deep(&self) -> String {
+        ParseError::TooDeep.to_string()
+    }
+
+    fn unsupported_version(&self, version: &serde_json::Value) -> String {
+        ParseError::UnsupportedVersion(version.clone()).to_string()
+    }
+
+    fn parse_error(&self, error: &ParseError) -> String {
+        match error {
+            ParseError::TooLarge => self.too_large(),
+            ParseError::TooDeep => self.too_deep(),
+            ParseError::Invalid(err) => self.invalid_value(&err.path().to_string(), err.inner()),
+            ParseError::UnsupportedVersion(version) => self.unsupported_version(version),
+        }
+    }
+}
+
+/// The default messages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct English;
+
+impl Messages for English {}
+
+impl Violation {
+    /// The field the violation is reported on.
+    pub fn field(&self) -> &'static str {
+        match self {
+            Violation::Rule(
+                Rule::Requires(field, _)
+                | Rule::Exclusive(field, _)
+                | Rule::RequiresValue(field, _),
+            ) => field,
+            Violation::Field { field, .. } => field,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PatchLimits, Update};
+
+    struct German;
+
+    impl Messages for German {
+        fn violation(&self, violation: &Violation) -> String {
+            match violation {
+                Violation::Rule(Rule::Requires(field, other)) => {
+                    format!("`{}` erfordert `{}`", field, other)
+                }
+                _ => English.violation(violation),
+            }
+        }
+
+        fn invalid_value(&self, path: &str, _error: &serde_json::Error) -> String {
+            format!("{}: ungültiger Wert", path)
+        }
+    }
+
+    #[test]
+    fn localized() {
+        let violation = Violation::Rule(Rule::Requires("one", "two"));
+        assert_eq!(violation.field(), "one");
+        assert_eq!(German.violation(&violation), "`one` erfordert `two`");
+        assert_eq!(English.violation(&violation), "`one` requires `two`");
+
+        let violation = Violation::Rule(Rule::Exclusive("one", "two"));
+        assert_eq!(
+            German.violation(&violation),
+            "`one` and `two` can't both be set"
+        );
+
+        let err = Update::from_json_with_limits(
+            r#"{ "profile": { "bio": 1 } }"#,
+            &PatchLimits::default(),
+        )
+        .unwrap_err();
+        assert_eq!(German.parse_error(&err), "profile.bio: ungültiger Wert");
+        assert_eq!(English.parse_error(&err), err.to_string());
+        assert_eq!(German.too_large(), "patch is too large");
+    }
+}
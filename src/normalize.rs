@@ -0,0 +1,203 @@
+//! `deserialize_with` functions for patch fields that normalize present strings.
+//!
+//! Like `deserialize_some` these must be combined with `#[serde(default)]` so missing fields stay
+//! `None`. `null` is left as is.
+//!
+//! `Pipeline` does the same after deserializing, for normalization that's configured per call
+//! rather than baked into the patch type.
+
+use crate::{ProfileUpdate, Update};
+use serde::{Deserialize, Deserializer};
+use unicode_normalization::UnicodeNormalization;
+
+/// Remove leading and trailing whitespace.
+pub(crate) fn trim<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    normalized(deserializer, |value| value.trim().to_owned())
+}
+
+/// Trim and lowercase, for values such as emails and usernames.
+pub(crate) fn lowercase<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    normalized(deserializer, |value| value.trim().to_lowercase())
+}
+
+/// Trim and replace runs of whitespace with a single space.
+pub(crate) fn collapse_whitespace<'de, D>(
+    deserializer: D,
+) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    normalized(deserializer, |value| {
+        value.split_whitespace().collect::<Vec<_>>().join(" ")
+    })
+}
+
+/// Treat `""` as `null`, for clients that send an empty string when a text field is cleared.
+pub(crate) fn empty_as_null<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(Some(value.filter(|value| !value.is_empty())))
+}
+
+fn normalized<'de, D, F>(deserializer: D, f: F) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+    F: FnOnce(&str) -> String,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(Some(value.as_deref().map(f)))
+}
+
+/// A single step of a `Pipeline`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Step {
+    Trim,
+    Lowercase,
+    CollapseWhitespace,
+    /// Unicode normalization form C, so visually identical strings compare equal.
+    Nfc,
+    Custom(fn(String) -> String),
+}
+
+impl Step {
+    fn apply(self, value: String) -> String {
+        match self {
+            Step::Trim => value.trim().to_owned(),
+            Step::Lowercase => value.to_lowercase(),
+            Step::CollapseWhitespace => value.split_whitespace().collect::<Vec<_>>().join(" "),
+            Step::Nfc => value.nfc().collect(),
+            Step::Custom(f) => f(value),
+        }
+    }
+}
+
+/// Fields a `Pipeline` can be applied to.
+pub(crate) const TEXT_FIELDS: &[&str] = &["one", "two", "locale", "profile.bio"];
+
+/// Steps applied in order to a present string. `null` and missing values are left alone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pipeline(Vec<Step>);
+
+impl Pipeline {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn then(mut self, step: Step) -> Self {
+        self.0.push(step);
+        self
+    }
+
+    pub(crate) fn apply(&self, value: String) -> String {
+        self.0.iter().fold(value, |value, step| step.apply(value))
+    }
+
+    fn apply_to(&self, field: &mut Option<Option<String>>) {
+        if let Some(Some(value)) = field {
+            *value = self.apply(std::mem::take(value));
+        }
+    }
+}
+
+impl Update {
+    /// Run each pipeline over its field. Fields that aren't text are ignored, `Options::check_fields`
+    /// rejects them.
+    pub(crate) fn normalized(mut self, pipelines: &[(&str, Pipeline)]) -> Self {
+        for (field, pipeline) in pipelines {
+            match *field {
+                "one" => pipeline.apply_to(&mut self.one),
+                "two" => pipeline.apply_to(&mut self.two),
+                "locale" => pipeline.apply_to(&mut self.locale),
+                "profile.bio" => {
+                    if let Some(ProfileUpdate { bio }) = &mut self.profile {
+                        pipeline.apply_to(bio);
+                    }
+                }
+                _ => {}
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(serde::Deserialize)]
+    struct Payload {
+        #[serde(default, deserialize_with = "trim")]
+        trimmed: Option<Option<String>>,
+        #[serde(default, deserialize_with = "lowercase")]
+        lowercased: Option<Option<String>>,
+        #[serde(default, deserialize_with = "collapse_whitespace")]
+        collapsed: Option<Option<String>>,
+        #[serde(default, deserialize_with = "empty_as_null")]
+        cleared: Option<Option<String>>,
+    }
+
+    #[test]
+    fn normalizes_present_values() {
+        let payload = serde_json::from_value::<Payload>(json!({
+            "trimmed": "  a  b ",
+            "lowercased": " Bob@Example.com",
+            "collapsed": " a \t b\n\nc ",
+        }))
+        .unwrap();
+        assert_eq!(payload.trimmed, Some(Some("a  b".to_owned())));
+        assert_eq!(payload.lowercased, Some(Some("bob@example.com".to_owned())));
+        assert_eq!(payload.collapsed, Some(Some("a b c".to_owned())));
+
+        let payload = serde_json::from_value::<Payload>(json!({ "trimmed": null })).unwrap();
+        assert_eq!(payload.trimmed, Some(None));
+        assert_eq!(payload.lowercased, None);
+    }
+
+    #[test]
+    fn pipeline() {
+        let pipeline = Pipeline::new()
+            .then(Step::Trim)
+            .then(Step::Lowercase)
+            .then(Step::Nfc)
+            .then(Step::Custom(|value| value.replace('.', "")));
+        assert_eq!(pipeline.apply(" Jo\u{0301}.E ".to_owned()), "j\u{f3}e");
+
+        let update = serde_json::from_value::<Update>(json!({
+            "one": " A ",
+            "two": null,
+            "profile": { "bio": "  B  " },
+        }))
+        .unwrap()
+        .normalized(&[
+            ("one", pipeline.clone()),
+            ("two", pipeline.clone()),
+            ("locale", pipeline.clone()),
+            ("profile.bio", Pipeline::new().then(Step::Trim)),
+        ]);
+        assert_eq!(update.one, Some(Some("a".to_owned())));
+        assert_eq!(update.two, Some(None));
+        assert_eq!(update.locale, None);
+        assert_eq!(update.profile.unwrap().bio, Some(Some("B".to_owned())));
+    }
+
+    #[test]
+    fn empty_strings_are_null() {
+        let payload = serde_json::from_value::<Payload>(json!({ "cleared": "" })).unwrap();
+        assert_eq!(payload.cleared, Some(None));
+
+        let payload = serde_json::from_value::<Payload>(json!({ "cleared": " " })).unwrap();
+        assert_eq!(payload.cleared, Some(Some(" ".to_owned())));
+
+        let payload = serde_json::from_value::<Payload>(json!({})).unwrap();
+        assert_eq!(payload.cleared, None);
+    }
+}
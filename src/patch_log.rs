@@ -0,0 +1,140 @@
+use crate::{DbPool, Error, InternalId, Options, Update, User, DEFAULT_LOCALE};
+use serde_json::Value;
+use std::time::SystemTime;
+use tokio_postgres::{types::Json, Transaction};
+
+/// Who made a patch, stored next to it in `patches`.
+#[derive(Debug, Clone, Default)]
+pub struct PatchMeta {
+    pub actor: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Store the patch as it was applied in `patches`.
+///
+/// Missing fields are left out of the stored JSON and `null` fields are kept so the patch means
+/// the same thing when read back.
+pub(crate) async fn record(
+    tx: &Transaction<'_>,
+    internal_id: InternalId,
+    patch: &Update,
+    meta: &PatchMeta,
+) -> Result<(), Error> {
+    tx.execute(
+        r#"
+        insert into patches (internal_id, patch, actor, request_id)
+        values ($1, $2, $3, $4)
+        "#,
+        &[&internal_id, &Json(patch), &meta.actor, &meta.request_id],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Patches stored for the user after `since`, oldest first, decrypted with `options.encryption`.
+async fn patches_since(
+    pool: &DbPool,
+    internal_id: InternalId,
+    since: SystemTime,
+    options: &Options,
+) -> Result<Vec<Update>, Error> {
+    let con = pool.get().await?;
+
+    let rows = con
+        .query(
+            r#"
+            select patch
+            from patches
+            where internal_id = $1 and applied_at > $2
+            order by patch_id
+            "#,
+            &[&internal_id, &since],
+        )
+        .await?;
+
+    rows.iter()
+        .map(|row| {
+            let Json(patch) = row.get::<_, Json<Value>>("patch");
+            let patch = serde_json::from_value::<Update>(patch).map_err(|_| Error::InvalidPatch)?;
+            match &options.encryption {
+                Some(encryption) => patch.decrypted(encryption),
+                None => Ok(patch),
+            }
+        })
+        .collect()
+}
+
+/// Rebuild the user by applying the patches stored after `since` to `snapshot`, which should be
+/// the user as it was at `since`, read with the same `options`.
+///
+/// Nothing is written to the database.
+pub(crate) async fn rebuild(
+    pool: &DbPool,
+    mut snapshot: User,
+    since: SystemTime,
+    options: &Options,
+) -> Result<User, Error> {
+    for patch in patches_since(pool, snapshot.internal_id, since, options).await? {
+        snapshot.apply(&patch);
+    }
+    Ok(snapshot)
+}
+
+/// Apply the patches stored after `since` to the user again, for example after restoring a backup
+/// taken at `since`.
+///
+/// The patches are applied with `options`, so use the ones they were first applied with. They
+/// aren't stored again, so `Options::log` and `Options::idempotency_key` must not be set or this
+/// fails with `Error::InvalidConfig`.
+pub(crate) async fn reapply(
+    pool: &DbPool,
+    internal_id: InternalId,
+    since: SystemTime,
+    options: &Options,
+) -> Result<(), Error> {
+    if options.log.is_some() {
+        return Err(Error::InvalidConfig("log"));
+    }
+    if options.idempotency_key.is_some() {
+        return Err(Error::InvalidConfig("idempotency_key"));
+    }
+
+    for patch in patches_since(pool, internal_id, since, options).await? {
+        patch
+            .insert_or_update_with_options(internal_id, pool, options)
+            .await?;
+    }
+    Ok(())
+}
+
+impl User {
+    /// Apply the patch in memory the same way `insert_or_update` updates an existing row.
+    pub(crate) fn apply(&mut self, patch: &Update) {
+        if let Some(one) = &patch.one {
+            self.one = one.clone();
+        }
+        if let Some(two) = &patch.two {
+            self.two = two.clone();
+        }
+        if let Some(metadata) = &patch.metadata {
+            self.metadata = metadata.clone();
+        }
+        if let Some(status) = patch.status {
+            self.status = status;
+        }
+        if let Some(locale) = &patch.locale {
+            // `null` resets to the column default
+            self.locale = locale.clone().unwrap_or_else(|| DEFAULT_LOCALE.to_owned());
+        }
+        if let Some(organization_id) = patch.organization_id {
+            self.organization_id = organization_id;
+        }
+        if let Some(bio) = patch
+            .profile
+            .as_ref()
+            .and_then(|profile| profile.bio.as_ref())
+        {
+            self.bio = bio.clone();
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{tls::Tls, DbPool, Error};
+use bb8_postgres::{bb8, PostgresConnectionManager};
+use std::{env, str::FromStr, time::Duration};
+use tokio_postgres::{
+    config::SslMode,
+    tls::{MakeTlsConnect, TlsConnect},
+    Config, Socket,
+};
+
+/// Configuration for building a `DbPool`.
+///
+/// ```no_run
+/// # async fn connect() -> Result<(), upsert_sql::Error> {
+/// let pool = upsert_sql::PoolConfig::from_url("postgres://localhost/testing")?
+///     .max_size(32)
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    config: Config,
+    max_size: u32,
+    connection_timeout: Duration,
+    settings: Vec<(String, String)>,
+    tls: Tls,
+}
+
+impl PoolConfig {
+    pub fn new(config: Config) -> Self {
+        PoolConfig {
+            config,
+            max_size: 10,
+            connection_timeout: Duration::from_secs(30),
+            settings: Vec::new(),
+            tls: Tls::none(),
+        }
+    }
+
+    /// Parse a `postgres://` URL or a libpq style `key=value` connection string.
+    pub fn from_url(url: &str) -> Result<Self, Error> {
+        Ok(Self::new(Config::from_str(url)?))
+    }
+
+    /// Read `DATABASE_URL`, falling back to the standard `PGHOST`, `PGPORT`, `PGUSER`,
+    /// `PGPASSWORD`, and `PGDATABASE` variables if it isn't set.
+    pub fn from_env() -> Result<Self, Error> {
+        if let Ok(url) = env::var("DATABASE_URL") {
+            return Self::from_url(&url);
+        }
+
+        let mut config = Config::new();
+        config.host(env::var("PGHOST").unwrap_or_else(|_| "localhost".to_owned()));
+        if let Ok(port) = env::var("PGPORT") {
+            let port = port.parse().map_err(|_| Error::InvalidConfig("PGPORT"))?;
+            config.port(port);
+        }
+        if let Ok(user) = env::var("PGUSER") {
+            config.user(&user);
+        }
+        if let Ok(password) = env::var("PGPASSWORD") {
+            config.password(password);
+        }
+        if let Ok(dbname) = env::var("PGDATABASE") {
+            config.dbname(&dbname);
+        }
+        Ok(Self::new(config))
+    }
+
+    /// Maximum number of connections in the pool. Defaults to 10.
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long to wait when connecting or checking out a connection. Defaults to 30 seconds.
+    pub fn connection_timeout(mut self, connection_timeout: Duration) -> Self {
+        self.connection_timeout = connection_timeout;
+        self
+    }
+
+    /// Name shown for the connections in `pg_stat_activity`.
+    pub fn application_name(mut self, application_name: &str) -> Self {
+        self.config.application_name(application_name);
+        self
+    }
+
+    /// Schemas to resolve unqualified table names in, in order.
+    pub fn search_path(self, schemas: &[&str]) -> Self {
+        let search_path = schemas.join(", ");
+        self.setting("search_path", &search_path)
+    }
+
+    /// Set a session level parameter, such as `statement_timeout` or a custom `app.tenant`, on
+    /// every connection.
+    ///
+    /// The parameters are sent when connecting so they apply before the connection is first
+    /// checked out.
+    pub fn setting(mut self, name: &str, value: &str) -> Self {
+        self.settings.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Connect with TLS, using a connector such as `postgres_native_tls::MakeTlsConnector` or
+    /// `tokio_postgres_rustls::MakeRustlsConnect`.
+    ///
+    /// Whether TLS is required is up to `sslmode` in the connection string. Without a connector
+    /// connections are never encrypted, and `sslmode=require` fails to connect.
+    pub fn tls<T>(mut self, tls: T) -> Self
+    where
+        T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+        T::Stream: Send + Sync + 'static,
+        T::TlsConnect: Send + 'static,
+        <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        self.tls = Tls::new(tls);
+        self
+    }
+
+    pub async fn build(mut self) -> Result<DbPool, Error> {
+        self.config.connect_timeout(self.connection_timeout);
+
+        // without a connector don't ask servers for TLS, like `NoTls`, as it can't be used
+        if !self.tls.is_enabled() && self.config.get_ssl_mode() == SslMode::Prefer {
+            self.config.ssl_mode(SslMode::Disable);
+        }
+
+        if !self.settings.is_empty() {
+            // keep any options from the connection string
+            let mut options = self.config.get_options().unwrap_or_default().to_owned();
+            for (name, value) in &self.settings {
+                if !options.is_empty() {
+                    options.push(' ');
+                }
+                options.push_str(&format!("-c {}={}", escape(name), escape(value)));
+            }
+            self.config.options(&options);
+        }
+
+        let manager = PostgresConnectionManager::new(self.config, self.tls);
+
+        let pool = bb8::Pool::builder()
+            .max_size(self.max_size)
+            .connection_timeout(self.connection_timeout)
+            .build(manager)
+            .await?;
+
+        Ok(pool)
+    }
+}
+
+/// Escape spaces and backslashes the way libpq expects in `options`.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(' ', "\\ ")
+}
+
+/// Check that a connection can be checked out and a trivial query runs within `deadline`.
+///
+/// Intended for readiness probes.
+pub async fn health_check(pool: &DbPool, deadline: Duration) -> Result<(), Error> {
+    let check = async {
+        let con = pool.get().await?;
+        con.simple_query("select 1").await?;
+        Ok(())
+    };
+
+    tokio::time::timeout(deadline, check)
+        .await
+        .map_err(|_| Error::Timeout)?
+}
@@ -0,0 +1,240 @@
+use serde::de::{self, value, Deserialize, Deserializer, Visitor};
+use std::{fmt, marker::PhantomData};
+
+/// A field that must be present but may be `null`.
+///
+/// Unlike `Option<T>` a missing field is an error rather than `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Required<T>(pub Option<T>);
+
+impl<'de, T> Deserialize<'de> for Required<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // `deserialize_option` can't tell `null` and missing apart since serde calls `visit_none`
+        // for both. `deserialize_any` errors with "missing field" for missing fields so go
+        // through that instead
+        deserializer
+            .deserialize_any(NullableVisitor(PhantomData))
+            .map(Required)
+    }
+}
+
+/// A field that may be missing but must not be `null`.
+///
+/// Must be combined with `#[serde(default)]` so missing fields become `Maybe(None)`. Serde doesn't
+/// tell `Deserialize` which field it is reading, so to name the field in the `null` error use
+/// `Maybe::deserialize_named` from a `#[serde(deserialize_with)]` helper:
+///
+/// ```
+/// use serde::{Deserialize, Deserializer};
+/// use upsert_sql::Maybe;
+///
+/// #[derive(Deserialize)]
+/// struct Payload {
+///     #[serde(default, deserialize_with = "name")]
+///     name: Maybe<String>,
+/// }
+///
+/// fn name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Maybe<String>, D::Error> {
+///     Maybe::deserialize_named(deserializer, "name")
+/// }
+///
+/// let err = serde_json::from_str::<Payload>(r#"{ "name": null }"#).err().unwrap();
+/// assert!(err.to_string().contains("`name`"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Maybe<T>(pub Option<T>);
+
+impl<T> Default for Maybe<T> {
+    fn default() -> Self {
+        Maybe(None)
+    }
+}
+
+impl<T> Maybe<T> {
+    /// Like `Deserialize::deserialize` but the `null` error names `field`.
+    pub fn deserialize_named<'de, D>(deserializer: D, field: &'static str) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        match deserializer.deserialize_any(NullableVisitor(PhantomData))? {
+            Some(value) => Ok(Maybe(Some(value))),
+            None => Err(de::Error::custom(format_args!(
+                "invalid type: null, field `{}` may be omitted but must not be null",
+                field
+            ))),
+        }
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Maybe<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match deserializer.deserialize_any(NullableVisitor(PhantomData))? {
+            Some(value) => Ok(Maybe(Some(value))),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::Unit,
+                &"a value, field may be omitted but must not be null",
+            )),
+        }
+    }
+}
+
+/// Visitor that maps `null` to `None` and forwards everything else to `T`.
+struct NullableVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for NullableVisitor<T>
+where
+    T: Deserialize<'de>,
+{
+    type Value = Option<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a value or null")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(Some)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        T::deserialize(value::BoolDeserializer::new(v)).map(Some)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        T::deserialize(value::I64Deserializer::new(v)).map(Some)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        T::deserialize(value::U64Deserializer::new(v)).map(Some)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        T::deserialize(value::F64Deserializer::new(v)).map(Some)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        T::deserialize(value::StrDeserializer::new(v)).map(Some)
+    }
+
+    fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+        T::deserialize(value::BorrowedStrDeserializer::new(v)).map(Some)
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        T::deserialize(value::StringDeserializer::new(v)).map(Some)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        T::deserialize(value::BytesDeserializer::new(v)).map(Some)
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+        T::deserialize(value::BorrowedBytesDeserializer::new(v)).map(Some)
+    }
+
+    fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        T::deserialize(value::SeqAccessDeserializer::new(seq)).map(Some)
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        T::deserialize(value::MapAccessDeserializer::new(map)).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(serde::Deserialize)]
+    struct Payload {
+        reason: Required<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct MaybePayload {
+        #[serde(default, deserialize_with = "name")]
+        name: Maybe<String>,
+    }
+
+    fn name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Maybe<String>, D::Error> {
+        Maybe::deserialize_named(deserializer, "name")
+    }
+
+    #[test]
+    fn required() {
+        let payload = serde_json::from_value::<Payload>(json!({ "reason": "typo" })).unwrap();
+        assert_eq!(payload.reason, Required(Some("typo".to_owned())));
+
+        let payload = serde_json::from_value::<Payload>(json!({ "reason": null })).unwrap();
+        assert_eq!(payload.reason, Required(None));
+
+        let err = serde_json::from_value::<Payload>(json!({})).err().unwrap();
+        assert_eq!(err.to_string(), "missing field `reason`");
+
+        let err = serde_json::from_str::<Payload>("{}").err().unwrap();
+        assert!(err.to_string().starts_with("missing field `reason`"));
+
+        let err = serde_json::from_value::<Payload>(json!({ "reason": 1 }))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid type: integer `1`, expected a string"
+        );
+    }
+
+    #[test]
+    fn maybe() {
+        let payload = serde_json::from_value::<MaybePayload>(json!({ "name": "Bob" })).unwrap();
+        assert_eq!(payload.name, Maybe(Some("Bob".to_owned())));
+
+        let payload = serde_json::from_value::<MaybePayload>(json!({})).unwrap();
+        assert_eq!(payload.name, Maybe(None));
+
+        let err = serde_json::from_str::<MaybePayload>(r#"{ "name": null }"#)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid type: null, field `name` may be omitted but must not be null at line 1 column 16"
+        );
+
+        let err = serde_json::from_value::<MaybePayload>(json!({ "name": 1 }))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid type: integer `1`, expected a string"
+        );
+    }
+}
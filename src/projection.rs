@@ -0,0 +1,112 @@
+use crate::{encrypt, tenant_condition, DbPool, Error, InternalId, Options};
+use serde_json::{Map, Value};
+use tokio_postgres::types::{Json, ToSql};
+
+/// A column that can be fetched with `fetch_columns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum UserColumn {
+    Id,
+    One,
+    Two,
+    Metadata,
+    DeletedAt,
+    Status,
+    Locale,
+    OrganizationId,
+    Bio,
+}
+
+impl UserColumn {
+    pub(crate) const ALL: &'static [UserColumn] = &[
+        UserColumn::Id,
+        UserColumn::One,
+        UserColumn::Two,
+        UserColumn::Metadata,
+        UserColumn::DeletedAt,
+        UserColumn::Status,
+        UserColumn::Locale,
+        UserColumn::OrganizationId,
+        UserColumn::Bio,
+    ];
+
+    /// Name of the column, which is also its key in the fetched JSON.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            UserColumn::Id => "id",
+            UserColumn::One => "one",
+            UserColumn::Two => "two",
+            UserColumn::Metadata => "metadata",
+            UserColumn::DeletedAt => "deleted_at",
+            UserColumn::Status => "status",
+            UserColumn::Locale => "locale",
+            UserColumn::OrganizationId => "organization_id",
+            UserColumn::Bio => "bio",
+        }
+    }
+
+    /// Name of the field as it appears in patches.
+    pub(crate) fn field(self) -> &'static str {
+        match self {
+            UserColumn::Bio => "profile.bio",
+            column => column.name(),
+        }
+    }
+}
+
+/// Fetch only some columns of a user as a JSON object keyed by column name. Soft deleted users,
+/// and users outside `options.tenant`, are excluded.
+///
+/// Values are encoded the way postgres encodes them as JSON, so timestamps are strings. Fields in
+/// `options.encryption` are decrypted.
+pub(crate) async fn fetch_columns(
+    pool: &DbPool,
+    internal_id: InternalId,
+    columns: &[UserColumn],
+    options: &Options,
+) -> Result<Map<String, Value>, Error> {
+    // column names come from `UserColumn` so formatting them into the query is safe
+    let fields = columns
+        .iter()
+        .map(|column| format!("'{name}', {name}", name = column.name()))
+        .collect::<Vec<_>>();
+
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&internal_id];
+    let tenant = tenant_condition(options, &mut params);
+
+    let con = pool.get().await?;
+
+    let row = con
+        .query_opt(
+            format!(
+                r#"
+                select json_build_object({}) as projection
+                from users
+                left join user_profiles using (internal_id)
+                where internal_id = $1 and deleted_at is null{}
+                "#,
+                fields.join(", "),
+                tenant,
+            )
+            .as_str(),
+            &params,
+        )
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    let mut projection = match row.get::<_, Json<Value>>("projection").0 {
+        Value::Object(projection) => projection,
+        _ => unreachable!("json_build_object always returns an object"),
+    };
+
+    for column in [UserColumn::One, UserColumn::Two, UserColumn::Bio] {
+        if let Some(value) = projection.get_mut(column.name()) {
+            if let Value::String(stored) = value.take() {
+                let plaintext =
+                    encrypt::decrypt(options.encryption.as_ref(), column.field(), Some(stored))?;
+                *value = plaintext.map_or(Value::Null, Value::String);
+            }
+        }
+    }
+
+    Ok(projection)
+}
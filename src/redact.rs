@@ -0,0 +1,238 @@
+//! Printing patches and users without the values of sensitive fields.
+//!
+//! `Debug` for `Update`, `User`, `StaleField`, and `Conflict` always redacts `SENSITIVE`, so
+//! `{:?}` and `unwrap` don't leak personal data into logs.
+
+use crate::{Conflict, ProfileUpdate, StaleField, Update, User};
+use serde_json::Value;
+use std::fmt;
+
+/// Printed in place of a redacted value.
+pub(crate) const REDACTED: &str = "***";
+
+/// Fields holding personal data, the same ones `erase` can erase.
+pub(crate) const SENSITIVE: &[&str] = &["one", "two", "metadata", "profile.bio"];
+
+/// Debug formats a patch with the values of some fields, and always `SENSITIVE`, replaced by
+/// `***`.
+///
+/// Missing fields are left out and `null` is printed as `null`, so it's still clear what the
+/// patch does to a field, just not what it sets it to.
+pub(crate) struct Redacted<'a> {
+    patch: &'a Update,
+    fields: &'a [&'a str],
+}
+
+impl Update {
+    /// Format with `{:?}` with the values of `fields` redacted as well as `SENSITIVE`, such as
+    /// `Options::redact` for logs.
+    pub(crate) fn redacted<'a>(&'a self, fields: &'a [&'a str]) -> Redacted<'a> {
+        Redacted {
+            patch: self,
+            fields,
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Update");
+        let patch = self.patch;
+        self.field(&mut debug, "one", &patch.one);
+        self.field(&mut debug, "two", &patch.two);
+        self.field(&mut debug, "metadata", &patch.metadata);
+        self.field(&mut debug, "status", &patch.status);
+        self.field(&mut debug, "locale", &patch.locale);
+        self.field(&mut debug, "organization_id", &patch.organization_id);
+        if let Some(profile) = &patch.profile {
+            self.field(&mut debug, "profile.bio", &profile.bio);
+        }
+        debug.finish()
+    }
+}
+
+impl Redacted<'_> {
+    fn field<T: fmt::Debug>(
+        &self,
+        debug: &mut fmt::DebugStruct<'_, '_>,
+        name: &str,
+        value: &Option<Option<T>>,
+    ) {
+        match value {
+            None => {}
+            Some(None) => {
+                debug.field(name, &format_args!("null"));
+            }
+            Some(Some(_)) if self.fields.contains(&name) || SENSITIVE.contains(&name) => {
+                debug.field(name, &Hidden);
+            }
+            Some(Some(value)) => {
+                debug.field(name, value);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Update {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.redacted(&[]).fmt(f)
+    }
+}
+
+impl fmt::Debug for ProfileUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("ProfileUpdate");
+        match &self.bio {
+            None => {}
+            Some(None) => {
+                debug.field("bio", &format_args!("null"));
+            }
+            Some(Some(_)) => {
+                debug.field("bio", &Hidden);
+            }
+        }
+        debug.finish()
+    }
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("internal_id", &self.internal_id)
+            .field("one", &Sensitive(&self.one))
+            .field("two", &Sensitive(&self.two))
+            .field("metadata", &Sensitive(&self.metadata))
+            .field("deleted_at", &self.deleted_at)
+            .field("status", &self.status)
+            .field("locale", &self.locale)
+            .field("organization_id", &self.organization_id)
+            .field("bio", &Sensitive(&self.bio))
+            .finish()
+    }
+}
+
+impl fmt::Debug for StaleField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaleField")
+            .field("field", &self.field)
+            .field("expected", &value(self.field, &self.expected))
+            .field("current", &value(self.field, &self.current))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Conflict")
+            .field("field", &self.field)
+            .field("base", &value(self.field, &self.base))
+            .field("ours", &value(self.field, &self.ours))
+            .field("theirs", &value(self.field, &self.theirs))
+            .finish()
+    }
+}
+
+/// Debug formats a value that is always sensitive as `***`, or `None` if it is null.
+struct Sensitive<'a, T>(&'a Option<T>);
+
+impl<T> fmt::Debug for Sensitive<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => Hidden.fmt(f),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+/// Debug formats as `***`.
+struct Hidden;
+
+impl fmt::Debug for Hidden {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// `value`, or `Hidden` if `field` is sensitive and `value` isn't null.
+fn value<'a>(field: &str, value: &'a Value) -> &'a dyn fmt::Debug {
+    if SENSITIVE.contains(&field) && !value.is_null() {
+        &Hidden
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InternalId;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_values() {
+        let patch = serde_json::from_value::<Update>(json!({
+            "one": "secret",
+            "two": null,
+            "locale": "da",
+            "profile": { "bio": "private" },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            format!("{:?}", patch.redacted(&["locale"])),
+            r#"Update { one: ***, two: null, locale: ***, profile.bio: *** }"#
+        );
+        assert_eq!(
+            format!("{:?}", patch.redacted(&[])),
+            r#"Update { one: ***, two: null, locale: "da", profile.bio: *** }"#
+        );
+    }
+
+    #[test]
+    fn debug_hides_sensitive_values() {
+        let patch = serde_json::from_value::<Update>(json!({
+            "one": "secret",
+            "two": null,
+            "status": "active",
+            "profile": { "bio": "private" },
+        }))
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", patch),
+            r#"Update { one: ***, two: null, status: Active, profile.bio: *** }"#
+        );
+        assert_eq!(
+            format!("{:?}", patch.profile.unwrap()),
+            "ProfileUpdate { bio: *** }"
+        );
+
+        let mut user = User::with_defaults(1, InternalId(2));
+        user.one = Some("secret".to_owned());
+        assert_eq!(
+            format!("{:?}", user),
+            "User { id: 1, internal_id: InternalId(2), one: ***, two: None, metadata: None, \
+             deleted_at: None, status: Some(Active), locale: \"en\", organization_id: None, \
+             bio: None }"
+        );
+
+        let stale = StaleField {
+            field: "one",
+            expected: json!("secret"),
+            current: Value::Null,
+        };
+        assert_eq!(
+            format!("{:?}", stale),
+            r#"StaleField { field: "one", expected: ***, current: Null }"#
+        );
+        let stale = StaleField {
+            field: "locale",
+            expected: json!("da"),
+            current: json!("en"),
+        };
+        assert_eq!(
+            format!("{:?}", stale),
+            r#"StaleField { field: "locale", expected: String("da"), current: String("en") }"#
+        );
+    }
+}
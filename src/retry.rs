@@ -0,0 +1,168 @@
+//! When and how often failed transactions are retried.
+
+use crate::{is_retryable, Error, MAX_ATTEMPTS};
+use std::{future::Future, time::Duration};
+
+/// Decides which errors are retried, how many times, and how long to wait in between.
+///
+/// Every method defaults to the built in behavior: serialization failures and deadlocks are
+/// retried straight away, up to `MAX_ATTEMPTS` attempts in total.
+pub(crate) trait RetryPolicy: Send + Sync {
+    /// Whether the whole transaction can be tried again after `err`.
+    fn is_retryable(&self, err: &Error) -> bool {
+        is_retryable(err)
+    }
+
+    /// Attempts in total, the first one included.
+    fn max_attempts(&self) -> usize {
+        MAX_ATTEMPTS
+    }
+
+    /// How long to wait after attempt number `attempt` failed, counting from 1.
+    fn delay(&self, attempt: usize) -> Duration {
+        let _ = attempt;
+        Duration::ZERO
+    }
+
+    /// Called before each retry, for example to count retries in a metric.
+    fn on_retry(&self, attempt: usize, err: &Error, delay: Duration) {
+        let _ = (attempt, err, delay);
+    }
+}
+
+/// Exponential backoff, doubling the delay after each attempt up to `max`.
+///
+/// The default has no delay, which is the built in behavior.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Backoff {
+    pub(crate) max_attempts: usize,
+    pub(crate) initial: Duration,
+    pub(crate) max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            max_attempts: MAX_ATTEMPTS,
+            initial: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl RetryPolicy for Backoff {
+    fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1) as u32)
+            .unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Run `attempt` until it succeeds or `policy` gives up.
+pub(crate) async fn retrying<T, F, Fut>(
+    policy: &dyn RetryPolicy,
+    mut attempt: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(err) if attempts < policy.max_attempts() && policy.is_retryable(&err) => {
+                let delay = policy.delay(attempts);
+                policy.on_retry(attempts, &err, delay);
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Retries timeouts, recording each retry.
+    #[derive(Default)]
+    struct RetryTimeouts {
+        retries: Mutex<Vec<(usize, Duration)>>,
+    }
+
+    impl RetryPolicy for RetryTimeouts {
+        fn is_retryable(&self, err: &Error) -> bool {
+            matches!(err, Error::Timeout)
+        }
+
+        fn max_attempts(&self) -> usize {
+            3
+        }
+
+        fn delay(&self, attempt: usize) -> Duration {
+            Duration::from_millis(attempt as u64)
+        }
+
+        fn on_retry(&self, attempt: usize, _err: &Error, delay: Duration) {
+            self.retries.lock().unwrap().push((attempt, delay));
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let policy = RetryTimeouts::default();
+        let mut calls = 0;
+        let result = retrying(&policy, || {
+            calls += 1;
+            async { Err::<(), _>(Error::Timeout) }
+        })
+        .await;
+
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(calls, 3);
+        assert_eq!(
+            *policy.retries.lock().unwrap(),
+            vec![(1, Duration::from_millis(1)), (2, Duration::from_millis(2))]
+        );
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let policy = RetryTimeouts::default();
+        let mut calls = 0;
+        let result = retrying(&policy, || {
+            calls += 1;
+            async { Err::<(), _>(Error::NotFound) }
+        })
+        .await;
+
+        assert!(matches!(result, Err(Error::NotFound)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn backoff() {
+        let backoff = Backoff {
+            max_attempts: 10,
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let delays = (1..=5)
+            .map(|attempt| backoff.delay(attempt))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [10, 20, 40, 50, 50].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(Backoff::default().delay(3), Duration::ZERO);
+    }
+}
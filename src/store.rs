@@ -0,0 +1,140 @@
+use crate::{fetch, DbPool, Error, InternalId, Outcome, Update, User, UserStatus, DEFAULT_LOCALE};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+};
+
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Somewhere users can be patched and fetched.
+///
+/// Methods return boxed futures so the trait is object safe and the store can be picked at
+/// runtime as a `Box<dyn UserStore>`.
+pub(crate) trait UserStore: Send + Sync {
+    /// See `Update::insert_or_update`.
+    fn insert_or_update<'a>(
+        &'a self,
+        internal_id: InternalId,
+        patch: &'a Update,
+    ) -> BoxFuture<'a, Result<Outcome, Error>>;
+
+    /// See `fetch`.
+    fn fetch(&self, internal_id: InternalId) -> BoxFuture<'_, Result<User, Error>>;
+}
+
+impl UserStore for DbPool {
+    fn insert_or_update<'a>(
+        &'a self,
+        internal_id: InternalId,
+        patch: &'a Update,
+    ) -> BoxFuture<'a, Result<Outcome, Error>> {
+        Box::pin(patch.clone().insert_or_update(internal_id, self))
+    }
+
+    fn fetch(&self, internal_id: InternalId) -> BoxFuture<'_, Result<User, Error>> {
+        Box::pin(fetch(self, internal_id))
+    }
+}
+
+/// `UserStore` that keeps users in memory, for examples and tests.
+///
+/// Patches are applied one at a time so they are serialized just like with row locks in
+/// postgres. Soft deleted users behave like `DeletedPolicy::Fail`.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore {
+    users: Mutex<HashMap<InternalId, User>>,
+    next_id: AtomicI64,
+}
+
+impl UserStore for MemoryStore {
+    fn insert_or_update<'a>(
+        &'a self,
+        internal_id: InternalId,
+        patch: &'a Update,
+    ) -> BoxFuture<'a, Result<Outcome, Error>> {
+        let outcome = self.apply(internal_id, patch);
+        Box::pin(async move { outcome })
+    }
+
+    fn fetch(&self, internal_id: InternalId) -> BoxFuture<'_, Result<User, Error>> {
+        let user = self
+            .users
+            .lock()
+            .unwrap()
+            .get(&internal_id)
+            .filter(|user| user.deleted_at.is_none())
+            .cloned()
+            .ok_or(Error::NotFound);
+        Box::pin(async move { user })
+    }
+}
+
+impl MemoryStore {
+    fn apply(&self, internal_id: InternalId, patch: &Update) -> Result<Outcome, Error> {
+        let mut users = self.users.lock().unwrap();
+
+        match users.get_mut(&internal_id) {
+            Some(user) if user.deleted_at.is_some() => Err(Error::Deleted),
+            Some(user) => {
+                let before = user.clone();
+                user.apply(patch);
+                let changed_fields = changed_fields(&before, user);
+                if changed_fields.is_empty() {
+                    Ok(Outcome::Noop)
+                } else {
+                    Ok(Outcome::Updated { changed_fields })
+                }
+            }
+            None => {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+                let mut user = User::with_defaults(id, internal_id);
+                user.apply(patch);
+                users.insert(internal_id, user);
+                Ok(Outcome::Inserted { id })
+            }
+        }
+    }
+}
+
+impl User {
+    /// A user with every field set to the value `insert_or_update` gives missing fields.
+    pub(crate) fn with_defaults(id: i64, internal_id: InternalId) -> Self {
+        User {
+            id,
+            internal_id,
+            one: None,
+            two: None,
+            metadata: None,
+            deleted_at: None,
+            status: Some(UserStatus::Active),
+            locale: DEFAULT_LOCALE.to_owned(),
+            organization_id: None,
+            bio: None,
+        }
+    }
+}
+
+pub(crate) fn changed_fields(before: &User, after: &User) -> Vec<&'static str> {
+    let mut changed_fields = Vec::new();
+    let mut check = |field, changed| {
+        if changed {
+            changed_fields.push(field);
+        }
+    };
+    check("one", before.one != after.one);
+    check("two", before.two != after.two);
+    check("metadata", before.metadata != after.metadata);
+    check("status", before.status != after.status);
+    check("locale", before.locale != after.locale);
+    check(
+        "organization_id",
+        before.organization_id != after.organization_id,
+    );
+    check("profile.bio", before.bio != after.bio);
+    changed_fields
+}
@@ -0,0 +1,173 @@
+//! Assertions for tests that print which fields differ rather than two whole structs.
+
+use crate::{Outcome, Update, User};
+use serde_json::{Map, Value};
+
+/// Assert two patches have the same fields with the same values.
+macro_rules! assert_patch_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::test_support::patch_eq(&$left, &$right)
+    };
+}
+
+/// Assert the outcome is `Outcome::Noop`.
+macro_rules! assert_noop {
+    ($outcome:expr $(,)?) => {
+        $crate::test_support::noop(&$outcome)
+    };
+}
+
+/// Assert exactly these fields are in the patch, whether `null` or not.
+macro_rules! assert_present {
+    ($patch:expr, [$($field:expr),* $(,)?] $(,)?) => {
+        $crate::test_support::present(&$patch, &[$($field),*])
+    };
+}
+
+/// Assert the user's fields have the values in a `json!` object. Other fields aren't checked.
+macro_rules! assert_user {
+    ($user:expr, $($expected:tt)+) => {
+        $crate::test_support::user(&$user, serde_json::json!($($expected)+))
+    };
+}
+
+pub(crate) use {assert_noop, assert_patch_eq, assert_present, assert_user};
+
+const MISSING: &str = "<missing>";
+
+#[track_caller]
+pub(crate) fn patch_eq(left: &Update, right: &Update) {
+    let left = to_map(left);
+    let right = to_map(right);
+
+    let mut fields = left.keys().chain(right.keys()).collect::<Vec<_>>();
+    fields.sort();
+    fields.dedup();
+
+    let differences = fields
+        .into_iter()
+        .filter(|field| left.get(*field) != right.get(*field))
+        .map(|field| {
+            format!(
+                "  {}: {} != {}",
+                field,
+                show(left.get(field)),
+                show(right.get(field))
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if !differences.is_empty() {
+        panic!("patches differ:\n{}", differences.join("\n"));
+    }
+}
+
+#[track_caller]
+pub(crate) fn noop(outcome: &Outcome) {
+    if *outcome != Outcome::Noop {
+        panic!("expected a no-op, got {:?}", outcome);
+    }
+}
+
+#[track_caller]
+pub(crate) fn present(patch: &Update, expected: &[&str]) {
+    let present = to_map(patch).keys().cloned().collect::<Vec<_>>();
+    let mut expected = expected
+        .iter()
+        .map(|field| field.to_string())
+        .collect::<Vec<_>>();
+    expected.sort();
+
+    if present != expected {
+        panic!(
+            "expected fields {:?} to be present, got {:?}",
+            expected, present
+        );
+    }
+}
+
+#[track_caller]
+pub(crate) fn user(user: &User, expected: Value) {
+    let actual = serde_json::to_value(user).unwrap();
+    let expected = match expected {
+        Value::Object(expected) => expected,
+        other => panic!("expected fields must be an object, got {}", other),
+    };
+
+    let differences = expected
+        .iter()
+        .filter(|(field, value)| actual.get(field.as_str()) != Some(*value))
+        .map(|(field, value)| {
+            format!(
+                "  {}: expected {}, got {}",
+                field,
+                value,
+                show(actual.get(field.as_str()))
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if !differences.is_empty() {
+        panic!(
+            "user {} differs:\n{}",
+            user.internal_id,
+            differences.join("\n")
+        );
+    }
+}
+
+/// The patch's fields, with `profile.bio` flattened so nested differences are named.
+fn to_map(patch: &Update) -> Map<String, Value> {
+    let mut map = match serde_json::to_value(patch).unwrap() {
+        Value::Object(map) => map,
+        _ => unreachable!("patches serialize as objects"),
+    };
+    if let Some(Value::Object(profile)) = map.remove("profile") {
+        for (field, value) in profile {
+            map.insert(format!("profile.{}", field), value);
+        }
+    }
+    map
+}
+
+fn show(value: Option<&Value>) -> String {
+    value.map_or_else(|| MISSING.to_owned(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn passing() {
+        assert_patch_eq!(
+            update(json!({ "one": "1", "profile": { "bio": null } })),
+            update(json!({ "profile": { "bio": null }, "one": "1" })),
+        );
+        assert_noop!(Outcome::Noop);
+        assert_present!(
+            update(json!({ "two": null, "profile": { "bio": "hi" } })),
+            ["two", "profile.bio"]
+        );
+    }
+
+    #[test]
+    fn readable_diff() {
+        let panic = std::panic::catch_unwind(|| {
+            assert_patch_eq!(
+                update(json!({ "one": "1", "two": null })),
+                update(json!({ "one": "2", "profile": { "bio": null } })),
+            )
+        })
+        .unwrap_err();
+        assert_eq!(
+            panic.downcast_ref::<String>().unwrap(),
+            "patches differ:\n  one: \"1\" != \"2\"\n  profile.bio: <missing> != null\n  two: null != <missing>"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! A boxed TLS connector, so `DbPool` is the same type whichever TLS implementation it connects
+//! with.
+
+use crate::store::BoxFuture;
+use std::{
+    error::Error as StdError,
+    fmt, io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::{
+    tls::{self, ChannelBinding, MakeTlsConnect, TlsConnect},
+    NoTls, Socket,
+};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// How `DbPool` connections are secured, set with `PoolConfig::tls`.
+#[derive(Clone)]
+pub struct Tls {
+    make: Arc<dyn MakeConnect>,
+    enabled: bool,
+}
+
+impl Tls {
+    pub(crate) fn none() -> Self {
+        Tls {
+            make: Arc::new(NoTls),
+            enabled: false,
+        }
+    }
+
+    pub(crate) fn new<T>(tls: T) -> Self
+    where
+        T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+        T::Stream: Send + Sync + 'static,
+        T::TlsConnect: Send + 'static,
+        <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        Tls {
+            make: Arc::new(tls),
+            enabled: true,
+        }
+    }
+
+    /// Whether a TLS connector was given, rather than connecting in plain text.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("enabled", &self.enabled)
+            .finish()
+    }
+}
+
+impl MakeTlsConnect<Socket> for Tls {
+    type Stream = Stream;
+    type TlsConnect = Connect;
+    type Error = BoxError;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<Connect, BoxError> {
+        self.make.make(domain)
+    }
+}
+
+/// `MakeTlsConnect` with the connector and stream types erased.
+trait MakeConnect: Send + Sync {
+    fn make(&self, domain: &str) -> Result<Connect, BoxError>;
+}
+
+impl<T> MakeConnect for T
+where
+    T: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    T::Stream: Send + Sync + 'static,
+    T::TlsConnect: Send + 'static,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    fn make(&self, domain: &str) -> Result<Connect, BoxError> {
+        let connect = self.clone().make_tls_connect(domain).map_err(Into::into)?;
+        Ok(Connect(Box::new(move |socket| {
+            Box::pin(async move {
+                let stream = connect.connect(socket).await.map_err(Into::into)?;
+                Ok(Stream(Box::pin(stream)))
+            })
+        })))
+    }
+}
+
+/// The `TlsConnect` made by `Tls`.
+pub struct Connect(Box<dyn FnOnce(Socket) -> BoxFuture<'static, Result<Stream, BoxError>> + Send>);
+
+impl TlsConnect<Socket> for Connect {
+    type Stream = Stream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Stream, BoxError>>;
+
+    fn connect(self, socket: Socket) -> Self::Future {
+        (self.0)(socket)
+    }
+}
+
+/// The stream connected by `Tls`.
+pub struct Stream(Pin<Box<dyn tls::TlsStream + Send + Sync>>);
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_shutdown(cx)
+    }
+}
+
+impl tls::TlsStream for Stream {
+    fn channel_binding(&self) -> ChannelBinding {
+        self.0.channel_binding()
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{normalize, store::BoxFuture, Error, InternalId, Options, Update, FIELDS};
+use std::fmt;
+use tokio_postgres::Transaction;
+
+/// Whether a patch field was missing, `null`, or set to a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Presence {
+    Missing,
+    Null,
+    Value,
+}
+
+impl Presence {
+    fn of<T>(field: &Option<Option<T>>) -> Self {
+        match field {
+            None => Presence::Missing,
+            Some(None) => Presence::Null,
+            Some(Some(_)) => Presence::Value,
+        }
+    }
+
+    /// Present as either `null` or a value.
+    pub(crate) fn is_present(self) -> bool {
+        self != Presence::Missing
+    }
+}
+
+/// A rule relating several fields of a patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// If the first field is present, possibly as `null`, the second must be too.
+    Requires(&'static str, &'static str),
+    /// The fields must not both be present.
+    Exclusive(&'static str, &'static str),
+    /// If the first field is set to a value, the second must not be missing or `null`.
+    RequiresValue(&'static str, &'static str),
+}
+
+impl Rule {
+    fn fields(self) -> [&'static str; 2] {
+        match self {
+            Rule::Requires(field, other)
+            | Rule::Exclusive(field, other)
+            | Rule::RequiresValue(field, other) => [field, other],
+        }
+    }
+}
+
+/// Something wrong with the patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The patch broke a `Rule`.
+    Rule(Rule),
+    /// A `Validator` rejected the field.
+    Field {
+        field: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Rule(Rule::Requires(field, other)) => {
+                write!(f, "`{}` requires `{}`", field, other)
+            }
+            Violation::Rule(Rule::Exclusive(field, other)) => {
+                write!(f, "`{}` and `{}` can't both be set", field, other)
+            }
+            Violation::Rule(Rule::RequiresValue(field, other)) => {
+                write!(f, "`{}` requires a value for `{}`", field, other)
+            }
+            Violation::Field { field, message } => write!(f, "`{}` {}", field, message),
+        }
+    }
+}
+
+/// An async check of a single field that runs inside the update's transaction.
+///
+/// Only called when the field is present in the patch, `null` included. Since it sees the same
+/// snapshot as the update, checks such as uniqueness can't race with the write when the
+/// transaction isolation level is high enough.
+pub(crate) trait Validator: Send + Sync {
+    fn field(&self) -> &'static str;
+
+    /// Return `Ok(Some(message))` to reject the patch.
+    fn validate<'a>(
+        &'a self,
+        internal_id: InternalId,
+        patch: &'a Update,
+        tx: &'a Transaction<'_>,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>>;
+}
+
+impl Update {
+    pub(crate) fn presence(&self, field: &str) -> Presence {
+        let bio = self
+            .profile
+            .as_ref()
+            .and_then(|profile| profile.bio.as_ref());
+        match field {
+            "one" => Presence::of(&self.one),
+            "two" => Presence::of(&self.two),
+            "metadata" => Presence::of(&self.metadata),
+            "status" => Presence::of(&self.status),
+            "locale" => Presence::of(&self.locale),
+            "organization_id" => Presence::of(&self.organization_id),
+            "profile.bio" => match bio {
+                None => Presence::Missing,
+                Some(bio) => Presence::of(&Some(bio.as_ref())),
+            },
+            _ => Presence::Missing,
+        }
+    }
+
+    /// Check the patch against `rules`, returning every rule it breaks.
+    pub(crate) fn validate(&self, rules: &[Rule]) -> Result<(), Vec<Violation>> {
+        let violations = rules
+            .iter()
+            .filter(|rule| match **rule {
+                Rule::Requires(field, other) => {
+                    self.presence(field).is_present() && !self.presence(other).is_present()
+                }
+                Rule::Exclusive(field, other) => {
+                    self.presence(field).is_present() && self.presence(other).is_present()
+                }
+                Rule::RequiresValue(field, other) => {
+                    self.presence(field) == Presence::Value
+                        && self.presence(other) != Presence::Value
+                }
+            })
+            .map(|rule| Violation::Rule(*rule))
+            .collect::<Vec<_>>();
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Run every validator whose field is present, returning all rejections.
+    pub(crate) async fn run_validators(
+        &self,
+        internal_id: InternalId,
+        validators: &[std::sync::Arc<dyn Validator>],
+        tx: &Transaction<'_>,
+    ) -> Result<(), Error> {
+        let mut violations = Vec::new();
+        for validator in validators {
+            let field = validator.field();
+            if !self.presence(field).is_present() {
+                continue;
+            }
+            if let Some(message) = validator.validate(internal_id, self, tx).await? {
+                violations.push(Violation::Field { field, message });
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid(violations))
+        }
+    }
+}
+
+impl Options {
+    /// Fail with `Error::InvalidConfig` for the first field named in `rules` or `validators` that
+    /// patches don't have, or in `normalize` that isn't text. `Update::presence` treats unknown
+    /// fields as missing, so a misspelled rule would silently never or always apply, and a
+    /// misspelled validator or pipeline would never run.
+    pub(crate) fn check_fields(&self) -> Result<(), Error> {
+        let unknown = self
+            .rules
+            .iter()
+            .flat_map(|rule| rule.fields())
+            .chain(self.validators.iter().map(|validator| validator.field()))
+            .find(|field| !FIELDS.contains(field))
+            .or_else(|| {
+                self.normalize
+                    .iter()
+                    .map(|(field, _)| *field)
+                    .find(|field| !normalize::TEXT_FIELDS.contains(field))
+            });
+        match unknown {
+            Some(field) => Err(Error::InvalidConfig(field)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let rules = [
+            Rule::Requires("one", "two"),
+            Rule::Exclusive("status", "locale"),
+            Rule::RequiresValue("organization_id", "profile.bio"),
+        ];
+
+        assert!(update(json!({})).validate(&rules).is_ok());
+        assert!(update(json!({ "one": "1", "two": null }))
+            .validate(&rules)
+            .is_ok());
+        assert!(update(json!({ "organization_id": null }))
+            .validate(&rules)
+            .is_ok());
+
+        let violations = update(json!({
+            "one": "1",
+            "status": "active",
+            "locale": null,
+            "organization_id": 1,
+            "profile": { "bio": null },
+        }))
+        .validate(&rules)
+        .unwrap_err();
+        assert_eq!(
+            violations,
+            rules
+                .iter()
+                .copied()
+                .map(Violation::Rule)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(violations[0].to_string(), "`one` requires `two`");
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let options = Options {
+            rules: vec![
+                Rule::Requires("one", "two"),
+                Rule::RequiresValue("profile.bio", "bio"),
+            ],
+            ..Options::default()
+        };
+        assert!(matches!(
+            options.check_fields(),
+            Err(Error::InvalidConfig("bio"))
+        ));
+        assert!(Options::default().check_fields().is_ok());
+
+        struct Misspelled;
+
+        impl Validator for Misspelled {
+            fn field(&self) -> &'static str {
+                "emial"
+            }
+
+            fn validate<'a>(
+                &'a self,
+                _: InternalId,
+                _: &'a Update,
+                _: &'a Transaction<'_>,
+            ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+                Box::pin(async { Ok(None) })
+            }
+        }
+
+        let options = Options {
+            validators: vec![std::sync::Arc::new(Misspelled)],
+            ..Options::default()
+        };
+        assert!(matches!(
+            options.check_fields(),
+            Err(Error::InvalidConfig("emial"))
+        ));
+
+        // only text fields can be normalized
+        for field in ["profile.bio", "status"] {
+            let options = Options {
+                normalize: vec![(field, normalize::Pipeline::new())],
+                ..Options::default()
+            };
+            assert_eq!(options.check_fields().is_ok(), field == "profile.bio");
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Upgrading patches sent in older payload shapes, for clients that lag behind the API.
+//!
+//! Payloads are versioned from 1. A migration upgrades a payload from its version to the next one,
+//! for example by renaming a field, so an old payload runs through every migration after its
+//! version before it is parsed as the current `Update`.
+
+use crate::{ParseError, PatchLimits, Update};
+use serde_json::{Map, Value};
+
+/// Upgrades a payload by one version.
+pub(crate) type Migration = fn(&mut Map<String, Value>);
+
+/// Guesses the version of a payload that doesn't say.
+pub(crate) type Detect = fn(&Map<String, Value>) -> Option<u64>;
+
+/// Registered migrations and how to tell which version a payload is.
+#[derive(Debug, Clone)]
+pub(crate) struct PayloadVersions {
+    /// Field holding the version, removed before the payload is parsed.
+    field: &'static str,
+    /// `migrations[0]` upgrades version 1 to 2 and so on.
+    migrations: Vec<Migration>,
+    /// Guesses the version of payloads without `field`. They are assumed to be current if this
+    /// isn't set or returns `None`.
+    detect: Option<Detect>,
+}
+
+impl PayloadVersions {
+    pub(crate) fn new(field: &'static str) -> Self {
+        PayloadVersions {
+            field,
+            migrations: Vec::new(),
+            detect: None,
+        }
+    }
+
+    /// Add the migration from the current version to the next.
+    pub(crate) fn migration(mut self, migration: Migration) -> Self {
+        self.migrations.push(migration);
+        self
+    }
+
+    /// Guess the version of payloads that don't say, for example from a field that has since
+    /// been renamed.
+    pub(crate) fn detect(mut self, detect: Detect) -> Self {
+        self.detect = Some(detect);
+        self
+    }
+
+    /// The version payloads are upgraded to.
+    pub(crate) fn current(&self) -> u64 {
+        self.migrations.len() as u64 + 1
+    }
+
+    /// Parse a payload of any supported version.
+    ///
+    /// `limits` are checked first, like `Update::from_json_with_limits`. Fails with
+    /// `ParseError::UnsupportedVersion` for versions newer than `current` and version 0. Paths in
+    /// errors are paths in the upgraded payload.
+    pub(crate) fn parse(&self, json: &str, limits: &PatchLimits) -> Result<Update, ParseError> {
+        limits.check(json)?;
+
+        let deserializer = &mut serde_json::Deserializer::from_str(json);
+        let value: Value =
+            serde_path_to_error::deserialize(deserializer).map_err(ParseError::Invalid)?;
+        let mut payload = match value {
+            Value::Object(payload) => payload,
+            // not an object, let deserializing report it
+            other => return serde_path_to_error::deserialize(other).map_err(ParseError::Invalid),
+        };
+
+        let version = match payload.remove(self.field) {
+            Some(version) => version
+                .as_u64()
+                .ok_or(ParseError::UnsupportedVersion(version))?,
+            None => self
+                .detect
+                .and_then(|detect| detect(&payload))
+                .unwrap_or_else(|| self.current()),
+        };
+        if version == 0 || version > self.current() {
+            return Err(ParseError::UnsupportedVersion(version.into()));
+        }
+
+        for migration in &self.migrations[version as usize - 1..] {
+            migration(&mut payload);
+        }
+
+        serde_path_to_error::deserialize(Value::Object(payload)).map_err(ParseError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_patch_eq;
+    use serde_json::json;
+
+    /// Version 1 called `one` `name`.
+    fn rename_name(payload: &mut Map<String, Value>) {
+        if let Some(name) = payload.remove("name") {
+            payload.insert("one".to_owned(), name);
+        }
+    }
+
+    /// Version 2 sent the bio at the top level.
+    fn nest_bio(payload: &mut Map<String, Value>) {
+        if let Some(bio) = payload.remove("bio") {
+            payload.insert("profile".to_owned(), json!({ "bio": bio }));
+        }
+    }
+
+    fn versions() -> PayloadVersions {
+        PayloadVersions::new("version")
+            .migration(rename_name)
+            .migration(nest_bio)
+            .detect(|payload| payload.contains_key("name").then_some(1))
+    }
+
+    fn update(value: Value) -> Update {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn upgrades_old_payloads() {
+        let versions = versions();
+        assert_eq!(versions.current(), 3);
+
+        let patch = versions
+            .parse(
+                r#"{ "version": 1, "name": "1", "bio": null }"#,
+                &PatchLimits::default(),
+            )
+            .unwrap();
+        assert_patch_eq!(
+            patch,
+            update(json!({ "one": "1", "profile": { "bio": null } }))
+        );
+
+        let patch = versions
+            .parse(r#"{ "version": 2, "bio": "hi" }"#, &PatchLimits::default())
+            .unwrap();
+        assert_patch_eq!(patch, update(json!({ "profile": { "bio": "hi" } })));
+
+        // current payloads are left alone, so `bio` is an unknown field and ignored
+        let patch = versions
+            .parse(r#"{ "version": 3, "bio": "hi" }"#, &PatchLimits::default())
+            .unwrap();
+        assert_patch_eq!(patch, update(json!({})));
+    }
+
+    #[test]
+    fn detects_versions() {
+        let versions = versions();
+
+        let patch = versions
+            .parse(r#"{ "name": null }"#, &PatchLimits::default())
+            .unwrap();
+        assert_patch_eq!(patch, update(json!({ "one": null })));
+
+        let patch = versions
+            .parse(r#"{ "two": "2" }"#, &PatchLimits::default())
+            .unwrap();
+        assert_patch_eq!(patch, update(json!({ "two": "2" })));
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        let versions = versions();
+
+        for json in [
+            r#"{ "version": 4 }"#,
+            r#"{ "version": 0 }"#,
+            r#"{ "version": "2" }"#,
+        ] {
+            let err = versions.parse(json, &PatchLimits::default()).unwrap_err();
+            assert!(matches!(err, ParseError::UnsupportedVersion(_)), "{}", json);
+        }
+
+        let err = versions
+            .parse(r#"{ "version": 2, "one": 1 }"#, &PatchLimits::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "one: invalid type: integer `1`, expected a string"
+        );
+    }
+
+    #[test]
+    fn limits_are_checked_first() {
+        let versions = versions();
+        let limits = PatchLimits {
+            max_bytes: 40,
+            max_depth: 2,
+        };
+
+        let err = versions
+            .parse(r#"{ "version": 1, "name": [[1]] }"#, &limits)
+            .unwrap_err();
+        assert!(matches!(err, ParseError::TooDeep));
+
+        let json = format!(r#"{{ "version": 1, "name": "{}" }}"#, "a".repeat(40));
+        let err = versions.parse(&json, &limits).unwrap_err();
+        assert!(matches!(err, ParseError::TooLarge));
+    }
+}